@@ -0,0 +1,59 @@
+#[macro_use]
+extern crate criterion;
+extern crate rn;
+
+use criterion::Criterion;
+use rn::utils::delta;
+
+/// the length of the files, like a large build artifact
+const LENGTH: usize = 64 * 1024 * 1024;
+
+/// pseudo random bytes, which compress as badly as a binary file
+fn random(length: usize) -> Vec<u8> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..length)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// the old file with a few bytes changed, inserted and removed
+fn edited(old: &[u8]) -> Vec<u8> {
+    let mut new = old.to_vec();
+    for i in 0..16 {
+        new[i * LENGTH / 16] ^= 0xff;
+    }
+    new.splice(LENGTH / 3..LENGTH / 3, b"inserted".iter().cloned());
+    new.drain(LENGTH / 2..LENGTH / 2 + 4096);
+    new
+}
+
+fn bench_delta(c: &mut Criterion) {
+    let old = random(LENGTH);
+    let new = edited(&old);
+    let block_size = delta::block_size_for(LENGTH as u64);
+    let signature = delta::signature(&old, block_size);
+    let changes = delta::delta(&signature, &new);
+
+    let patched_old = old.clone();
+    c.bench_function("signature 64MiB", move |b| b.iter(|| delta::signature(&old, block_size)));
+    c.bench_function("delta 64MiB", move |b| b.iter(|| delta::delta(&signature, &new)));
+    c.bench_function("patch 64MiB", move |b| {
+        b.iter(|| {
+            let mut patched = Vec::with_capacity(LENGTH);
+            delta::patch(&patched_old, &changes, &mut patched).unwrap();
+            patched
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_delta
+}
+criterion_main!(benches);
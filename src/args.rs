@@ -1,22 +1,55 @@
 use clap;
 
+/// the arguments of the subcommands which work on a project on a server
+fn server_args() -> Vec<clap::Arg<'static, 'static>> {
+    vec![
+        clap::Arg::with_name("server")
+            .required(true)
+            .index(1)
+            .help("set the remote server name which comes from ~/.ssh/config or inner rule, `local`, or a container, e.g. docker://web/srv/app."),
+        clap::Arg::with_name("project")
+            .short("p")
+            .long("project")
+            .value_name("PROJECT")
+            .takes_value(true)
+            .help("set the project name to be deployed, by default the project containing the current directory, or `default`."),
+        clap::Arg::with_name("user")
+            .long("user")
+            .takes_value(true)
+            .help("set ssh username for remote host."),
+        clap::Arg::with_name("password")
+            .long("password")
+            .takes_value(true)
+            .help("set ssh password for remote host."),
+        clap::Arg::with_name("port")
+            .long("port")
+            .takes_value(true)
+            .help("set ssh port for remote host."),
+        clap::Arg::with_name("identity")
+            .short("i")
+            .long("indentity")
+            .takes_value(true)
+            .help("set ssh identity file path for remote host."),
+    ]
+}
+
 pub fn get_args() -> clap::ArgMatches<'static> {
     clap::App::new("rn")
         .global_settings(&[clap::AppSettings::ColoredHelp])
         .version(crate_version!())
         .author(crate_authors!())
         .about("a realtime file transformer.")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
         .arg(clap::Arg::with_name("project")
                  .short("p")
                  .long("project")
                  .value_name("PROJECT")
                  .takes_value(true)
-                 .default_value("default")
-                 .help("set the project name to be deployed!"))
+                 .help("set the project name to be deployed, by default the project containing the current directory, or `default`."))
         .arg(clap::Arg::with_name("server")
                  .required(true)
                  .index(1)
-                 .help("set the remote server name which comes from ~/.ssh/config or inner rule."))
+                 .help("set the remote server name which comes from ~/.ssh/config or inner rule, `local`, or a container, e.g. docker://web/srv/app."))
         .arg(clap::Arg::with_name("watch")
                  .short("w")
                  .long("watch")
@@ -58,10 +91,98 @@ pub fn get_args() -> clap::ArgMatches<'static> {
             .long("delete")
             .short("d")
             .help("delete the remote file in not exits in current folder or not."))
+        .arg(clap::Arg::with_name("since")
+            .long("since")
+            .value_name("REF")
+            .takes_value(true)
+            .help("sync only the files changed since a git commit, branch or tag."))
+        .arg(clap::Arg::with_name("bwlimit")
+            .long("bwlimit")
+            .value_name("RATE")
+            .takes_value(true)
+            .help("limit the bandwidth in KiB/s, or with a suffix like 1.5m, over the host settings."))
+        .arg(clap::Arg::with_name("output")
+            .long("output")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("write the sync results and watch events to stdout as lines of json."))
         .arg(clap::Arg::with_name("v")
             .short("v")
             .multiple(true)
             .help("see detail information")
         )
+        .subcommand(clap::SubCommand::with_name("daemon")
+            .about("watch all the projects with `watch = true` in the config file.")
+            .arg(clap::Arg::with_name("delete")
+                .long("delete")
+                .short("d")
+                .help("delete the remote file in not exits in current folder or not.")))
+        .subcommand(clap::SubCommand::with_name("status")
+            .about("show the watches of the running daemon."))
+        .subcommand(clap::SubCommand::with_name("pause")
+            .about("stop syncing a project in the running daemon.")
+            .arg(clap::Arg::with_name("project")
+                .required(true)
+                .index(1)
+                .help("the project to pause.")))
+        .subcommand(clap::SubCommand::with_name("resume")
+            .about("continue syncing a paused project, all if no project is given.")
+            .arg(clap::Arg::with_name("project")
+                .index(1)
+                .help("the project to resume.")))
+        .subcommand(clap::SubCommand::with_name("sync-now")
+            .about("sync a project of the running daemon right now.")
+            .arg(clap::Arg::with_name("project")
+                .required(true)
+                .index(1)
+                .help("the project to sync.")))
+        .subcommand(clap::SubCommand::with_name("history")
+            .about("show what was synced where and when.")
+            .arg(clap::Arg::with_name("project")
+                .short("p")
+                .long("project")
+                .takes_value(true)
+                .help("only the syncs of this project."))
+            .arg(clap::Arg::with_name("host")
+                .long("host")
+                .takes_value(true)
+                .help("only the syncs to this server."))
+            .arg(clap::Arg::with_name("limit")
+                .short("n")
+                .long("limit")
+                .takes_value(true)
+                .default_value("20")
+                .help("show the last n syncs.")))
+        .subcommand(clap::SubCommand::with_name("verify")
+            .about("compare the files on the server with the local ones.")
+            .args(&server_args()))
+        .subcommand(clap::SubCommand::with_name("config")
+            .about("inspect the config.")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(clap::SubCommand::with_name("show")
+                .about("show the config files, from the lowest to the highest priority.")
+                .arg(clap::Arg::with_name("resolved")
+                    .long("resolved")
+                    .help("show the merged config and where each value comes from.")))
+            .subcommand(clap::SubCommand::with_name("check")
+                .about("check the config for errors, unknown keys and missing paths.")))
+        .subcommand(clap::SubCommand::with_name("rollback")
+            .about("point current on the server back to the previous release.")
+            .args(&server_args()))
+        .subcommand(clap::SubCommand::with_name("agent")
+            .about("apply the changes sent on stdin under a directory, started on the server while watching.")
+            .setting(clap::AppSettings::Hidden)
+            .arg(clap::Arg::with_name("root")
+                .required(true)
+                .index(1)
+                .help("the directory the paths are relative to.")))
+        .subcommand(clap::SubCommand::with_name("delta")
+            .about("update a file with the delta sent on stdin, after sending its signature.")
+            .setting(clap::AppSettings::Hidden)
+            .arg(clap::Arg::with_name("file")
+                .required(true)
+                .index(1)
+                .help("the file to update.")))
         .get_matches()
 }
@@ -0,0 +1,253 @@
+use errors::*;
+use utils::{signal, toml_parser, util, watchdog};
+use super::{resolve, create_watchdog, HostOptions, SyncOptions};
+use notify::DebouncedEvent;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write, ErrorKind};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// how often the daemon checks for new control connections and signals
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// a WatchDog running in its own thread
+struct WatchHandle {
+    project: String,
+    server: String,
+    tx: Sender<DebouncedEvent>,
+    control: Arc<watchdog::Control>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// the unix socket used to talk to the daemon
+pub fn socket_path() -> Result<PathBuf> {
+    Ok(util::runtime_dir()?.join("daemon.sock"))
+}
+
+fn start_watch(config_path: &Path, project_name: &str, server: &str, delete: bool) -> Result<WatchHandle> {
+    let options = HostOptions::default();
+    let (project, host) = resolve(config_path, project_name, server, &options)?;
+    let mut watchdog = create_watchdog(config_path, project_name, server, options, SyncOptions::default(), project, host);
+    let tx = watchdog.tx.clone();
+    let control = watchdog.control.clone();
+    // the first event makes the WatchDog do the initial sync
+    tx.send(DebouncedEvent::Rescan).chain_err(|| "send initial sync event failed")?;
+    let name = format!("{}@{}", project_name, server);
+    let thread = thread::Builder::new().name(name.clone()).spawn(move || {
+        if let Err(e) = watchdog.start(delete) {
+            error!("{}: watch failed: {}", name, e);
+        }
+    })?;
+    Ok(WatchHandle {
+        project: project_name.to_string(),
+        server: server.to_string(),
+        tx,
+        control,
+        thread,
+    })
+}
+
+/// remove the socket left by a daemon which did not exit cleanly
+fn remove_stale_socket(socket: &Path) -> Result<()> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!("rn daemon is already running, socket: {:?}", socket);
+        }
+        fs::remove_file(socket)?;
+    }
+    Ok(())
+}
+
+/// watch every project with `watch = true` on each of its hosts until SIGINT or SIGTERM
+pub fn run(config_path: &Path, delete: bool) -> Result<()> {
+    let socket = socket_path()?;
+    remove_stale_socket(&socket)?;
+
+    let config = toml_parser::get_config(config_path)?;
+    let mut handles = Vec::new();
+    for project in config.projects.unwrap_or_default() {
+        if project.watch != Some(true) {
+            continue;
+        }
+        let hosts = project.hosts.clone().unwrap_or_default();
+        if hosts.is_empty() {
+            warn!("project {} has no hosts to sync to", project.name);
+        }
+        for server in hosts.iter() {
+            match start_watch(config_path, &project.name, server, delete) {
+                Ok(handle) => handles.push(handle),
+                Err(e) => error!("watch project {} on {} failed: {}", project.name, server, e),
+            }
+        }
+    }
+    if handles.is_empty() {
+        bail!("no project to watch, set `watch = true` and `hosts` for projects in {:?}", config_path);
+    }
+
+    let listener = UnixListener::bind(&socket)?;
+    listener.set_nonblocking(true)?;
+    info!("rn daemon started with {} watches, listen on {:?}", handles.len(), socket);
+    while !signal::shutdown_requested() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = serve(stream, &handles) {
+                    warn!("control connection failed: {}", e);
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => error!("accept control connection failed: {}", e),
+        }
+    }
+
+    info!("rn daemon stopping");
+    for handle in handles {
+        let _ = handle.thread.join();
+    }
+    fs::remove_file(&socket)?;
+    Ok(())
+}
+
+/// answer one command from `rn status/pause/resume/sync-now`
+fn serve(stream: UnixStream, handles: &[WatchHandle]) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+    debug!("control command: {:?}", command);
+    let reply = match execute(command.trim(), handles) {
+        Ok(reply) => reply,
+        Err(e) => format!("error: {}\n", e),
+    };
+    (&stream).write_all(reply.as_bytes())?;
+    Ok(())
+}
+
+fn execute(command: &str, handles: &[WatchHandle]) -> Result<String> {
+    let mut words = command.split_whitespace();
+    let action = words.next().unwrap_or("");
+    let project = words.next();
+    match action {
+        "status" => Ok(status(handles)),
+        "pause" | "resume" | "sync-now" => {
+            let selected: Vec<&WatchHandle> = handles
+                .iter()
+                .filter(|handle| project.map_or(true, |name| handle.project == name))
+                .collect();
+            if selected.is_empty() {
+                bail!("no watch for project {}", project.unwrap_or(""));
+            }
+            let mut reply = String::new();
+            for handle in selected {
+                let send = match action {
+                    "pause" => {
+                        handle.control.paused.store(true, Ordering::SeqCst);
+                        false
+                    }
+                    // catch up with the changes made while paused
+                    "resume" => handle.control.paused.swap(false, Ordering::SeqCst),
+                    _ => {
+                        if handle.control.paused.load(Ordering::SeqCst) {
+                            reply.push_str(&format!("{} on {} is paused, resume it first\n", handle.project, handle.server));
+                            continue;
+                        }
+                        true
+                    }
+                };
+                if send {
+                    handle.tx.send(DebouncedEvent::Rescan).chain_err(|| "watch is not running")?;
+                }
+                reply.push_str(&format!("{}: {} on {}\n", action, handle.project, handle.server));
+            }
+            Ok(reply)
+        }
+        _ => bail!("unknown command: {:?}", command),
+    }
+}
+
+fn status(handles: &[WatchHandle]) -> String {
+    let mut reply = format!(
+        "{:<16} {:<16} {:<8} {:>6}  {:<12} {}\n",
+        "PROJECT", "SERVER", "STATE", "SYNCS", "LAST SYNC", "LAST ERROR"
+    );
+    for handle in handles {
+        let state = if handle.control.paused.load(Ordering::SeqCst) {
+            "paused"
+        } else {
+            "watching"
+        };
+        let status = match handle.control.status.lock() {
+            Ok(status) => status.clone(),
+            Err(_) => watchdog::WatchStatus::default(),
+        };
+        let last_sync = match status.last_sync.and_then(|t| SystemTime::now().duration_since(t).ok()) {
+            Some(elapsed) => format!("{}s ago", elapsed.as_secs()),
+            None => "-".to_string(),
+        };
+        reply.push_str(&format!(
+            "{:<16} {:<16} {:<8} {:>6}  {:<12} {}\n",
+            handle.project,
+            handle.server,
+            state,
+            status.syncs,
+            last_sync,
+            status.last_error.unwrap_or_else(|| "-".to_string())
+        ));
+    }
+    reply
+}
+
+/// send a command to the running daemon and return its reply
+pub fn send_command(command: &str) -> Result<String> {
+    let socket = socket_path()?;
+    let mut stream = UnixStream::connect(&socket).chain_err(|| {
+        format!("connect to {:?} failed, is `rn daemon` running?", socket)
+    })?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    if reply.starts_with("error: ") {
+        bail!("{}", reply["error: ".len()..].trim());
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_execute() {
+        let (tx, rx) = channel();
+        let handle = WatchHandle {
+            project: "test".to_string(),
+            server: "ubuntu".to_string(),
+            tx,
+            control: Arc::new(watchdog::Control::default()),
+            thread: thread::spawn(|| {}),
+        };
+        let handles = vec![handle];
+
+        assert!(execute("pause other", &handles).is_err());
+        assert!(execute("stop", &handles).is_err());
+
+        execute("pause test", &handles).unwrap();
+        assert!(handles[0].control.paused.load(Ordering::SeqCst));
+        let reply = execute("sync-now test", &handles).unwrap();
+        assert!(reply.contains("paused"));
+        assert!(rx.try_recv().is_err());
+
+        // resume syncs the changes made while paused
+        execute("resume", &handles).unwrap();
+        assert!(!handles[0].control.paused.load(Ordering::SeqCst));
+        assert_eq!(rx.try_recv().unwrap(), DebouncedEvent::Rescan);
+
+        let reply = execute("status", &handles).unwrap();
+        assert!(reply.contains("watching"));
+    }
+}
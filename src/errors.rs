@@ -1,10 +1,12 @@
 extern crate toml;
 extern crate notify;
 extern crate regex;
+extern crate serde_json;
 
 use std::{self, io, num};
 use std::convert::From;
 use std::path::StripPrefixError;
+use utils::rsync::SyncReport;
 
 error_chain! {
     foreign_links {
@@ -16,5 +18,14 @@ error_chain! {
         PathError(StripPrefixError);
         NumParseError(num::ParseIntError);
         NotifyError(notify::Error);
+        Json(serde_json::Error);
+    }
+
+    errors {
+        /// rsync or the transfer replacing it failed, the report tells what was done
+        SyncFailed(report: Box<SyncReport>) {
+            description("sync failed")
+            display("sync {} to {} failed: {}", report.project, report.host, report.errors.join("; "))
+        }
     }
 }
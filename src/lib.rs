@@ -1,12 +1,17 @@
 pub mod utils;
 pub mod errors;
 pub mod my_logger;
+pub mod daemon;
+pub mod session;
 
 extern crate regex;
 extern crate serde;
 extern crate notify;
 extern crate toml;
 extern crate shellexpand;
+extern crate serde_json;
+extern crate chrono;
+extern crate sha2;
 
 
 #[macro_use]
@@ -22,52 +27,65 @@ extern crate error_chain;
 #[macro_use]
 extern crate serde_derive;
 
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
+
 use errors::*;
 use utils::*;
+use utils::sshconfig::SshConfigSource;
 use std::path::Path;
-use std::sync::mpsc::channel;
 use shellexpand::{tilde, tilde_with_context};
 
 
-fn start_watch(project: &toml_parser::Project, host: &sshconfig::Host, delete: bool) -> Result<()> {
-    let (tx, rx) = channel();
-    let mut watchdog = watchdog::WatchDog {
-        project,
-        host,
-        tx: tx,
-        rx: rx,
-    };
-    watchdog.start(delete)?;
-    Ok(())
+/// ssh settings given on the command line, they take precedence over the config file
+#[derive(Debug, Clone, Default)]
+pub struct HostOptions {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub port: Option<u16>,
+    pub identity: Option<String>,
 }
 
-pub fn run(
-    config_path: &Path,
-    project_name: &str,
-    server: &str,
-    watch: bool,
-    user: Option<&str>,
-    password: Option<&str>,
-    port: Option<u16>,
-    identity: Option<&str>,
-    delete: bool,
-) -> Result<()> {
-    let global_config = toml_parser::get_config(config_path)?;
-    debug!("global config: {:?}", global_config);
-    let mut project = toml_parser::get_project_info(project_name, &global_config)?;
-    debug!("get project: {:?}", project);
+/// what is synced and how, given on the command line over the config file
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// sync only the files changed since this git ref
+    pub since: Option<String>,
+    /// the bandwidth limit, over the one of the host
+    pub bwlimit: Option<String>,
+}
+
+impl SyncOptions {
+    /// set the options on a resolved project
+    pub fn apply(&self, project: &mut toml_parser::Project) -> Result<()> {
+        project.since = self.since.clone();
+        if let Some(ref bwlimit) = self.bwlimit {
+            let transfer = project.transfer.get_or_insert_with(Default::default);
+            transfer.bwlimit = Some(bwlimit.to_string());
+            transfer.validate()?;
+        }
+        Ok(())
+    }
+}
 
-    let ssh_conf_path = tilde("~/.ssh/config").into_owned();
-    let server_host = sshconfig::parse_ssh_config(ssh_conf_path)?;
+/// how to login a server: its settings in ~/.ssh/config or the global ones,
+/// with the ones given on the command line over them
+fn ssh_host(
+    server: &str,
+    global_config: &toml_parser::GlobalConfig,
+    options: &HostOptions,
+    ssh_config: &dyn sshconfig::SshConfigSource,
+) -> Result<sshconfig::Host> {
+    let server_host = ssh_config.hosts()?;
     debug!("server host: {:?}", server_host);
     let mut host: sshconfig::Host = match server_host.get(server) {
         Some(host) => {
             let mut host = host.clone();
             if host.identityfile.is_none() {
-                host.password = global_config.global_password;
-                if global_config.global_key.is_some() {
-                    host.identityfile =
-                        Some(Path::new(global_config.global_key.unwrap().as_str()).into());
+                host.password = global_config.global_password.clone();
+                if let Some(ref key) = global_config.global_key {
+                    host.identityfile = Some(Path::new(key.as_str()).into());
                 }
             }
             host
@@ -75,47 +93,114 @@ pub fn run(
         None => {
             //let hostname = sshconfig::get_ip();
             let hostname = sshconfig::get_ip(server)?;
-            let g_user = global_config.global_user;
+            let g_user = match options.user.clone().or(global_config.global_user.clone()) {
+                Some(user) => user,
+                None => bail!("no user to login {}, set global_user in the config or use --user", server),
+            };
             // TODO: get password or key file from input
             let identityfile = match global_config.global_key {
                 None => None,
                 Some(ref file) => Some(tilde(file).into_owned()),
             };
-            let g_password = global_config.global_password;
+            let g_password = global_config.global_password.clone();
             let port = global_config.global_port;
             sshconfig::Host::new(hostname, g_user, identityfile, g_password, port)
         }
     };
     // update user, password, identity file
-    if user.is_some() {
-        host.user = user.unwrap().to_string();
+    if let Some(ref user) = options.user {
+        host.user = user.to_string();
     }
 
-    match password {
-        Some(p) => {
+    match options.password {
+        Some(ref p) => {
             host.password = Some(p.to_string());
             host.identityfile = None;
         }
         None => {}
     }
 
-    match identity {
-        Some(i) => {
+    match options.identity {
+        Some(ref i) => {
             host.identityfile = Some(Path::new(i).to_path_buf());
             host.password = None;
         }
         None => {}
     }
 
-    match port {
+    match options.port {
         Some(p) => {
             host.port = p;
         }
         None => {}
     }
 
-    debug!("get host: {:?}, port {:?}", host, port);
+    Ok(host)
+}
+
+/// load the project from the config file and find out how to login the server
+pub fn resolve(
+    config_path: &Path,
+    project_name: &str,
+    server: &str,
+    options: &HostOptions,
+) -> Result<(toml_parser::Project, sshconfig::Host)> {
+    resolve_with(config_path, project_name, server, options, &sshconfig::SshConfigFile::user())
+}
+
+/// resolve with the hosts of ssh_config instead of the ones of ~/.ssh/config
+pub fn resolve_with(
+    config_path: &Path,
+    project_name: &str,
+    server: &str,
+    options: &HostOptions,
+    ssh_config: &dyn sshconfig::SshConfigSource,
+) -> Result<(toml_parser::Project, sshconfig::Host)> {
+    let global_config = toml_parser::get_config(config_path)?;
+    debug!("global config: {:?}", global_config);
+    let mut project = toml_parser::get_project_info(project_name, &global_config)?;
+    debug!("get project: {:?}", project);
+
+    let local = sshconfig::is_local_server(server)
+        || global_config.host_settings(server, server).and_then(|settings| settings.kind) == Some(sshconfig::HostKind::Local);
+    let mut host = if container::is_target(server) {
+        sshconfig::Host::container(server, container::parse(server)?)
+    } else if local {
+        sshconfig::Host::local()
+    } else {
+        ssh_host(server, &global_config, options, ssh_config)?
+    };
+    debug!("get host: {:?}, port {:?}", host, options.port);
+
+    // a container runs on this machine or behind the api of the cluster, not at an address
+    let address = if host.is_ssh() { host.hostname.as_str() } else { "localhost" };
+    let profile = global_config.apply_host(&mut project, server, address)?;
+    if let Some(path) = host.container.as_ref().and_then(|container| container.path.clone()) {
+        project.dest = path;
+    }
+    debug!("profile of {}: {}, transfer: {:?}", server, profile, project.transfer);
+
+    project.expand(&toml_parser::TemplateContext {
+        project: project.name.clone(),
+        host: server.to_string(),
+        user: host.user.clone(),
+        git_branch: None,
+    })?;
+    project.validate()?;
 
+    if host.is_local() {
+        project.dest = tilde(&project.dest).into_owned();
+        return Ok((project, host));
+    }
+    if let Some(ref mut container) = host.container {
+        // checked once, not before each sync
+        container.rsync = Some(container.has_rsync()?);
+        if project.dest.starts_with('~') {
+            let home = container.home()?;
+            project.dest = tilde_with_context(project.dest.as_str(), || Some(home.as_str())).into_owned();
+        }
+        return Ok((project, host));
+    }
     // change ~ to /home/user or /root in dest path
     let common_home = match host.user.as_str() {
         "root" => "/root".to_string(),
@@ -128,11 +213,181 @@ pub fn run(
     }).into_owned();
 
     project.dest = dest_root;
-    rsync::sync(&host, &project, delete)?;
+    Ok((project, host))
+}
+
+/// the project whose src contains the current directory, used when no project is given
+pub fn current_project(config_path: &Path) -> Option<String> {
+    let config = toml_parser::get_config(config_path).ok()?;
+    let dir = std::env::current_dir().ok()?;
+    let project = toml_parser::project_for_dir(&dir, &config)?;
+    debug!("project {} contains the current directory", project.name);
+    Some(project.name.clone())
+}
+
+/// the hostname a server name stands for, as recorded in the history
+fn resolve_hostname(server: &str) -> String {
+    if let Ok(hosts) = sshconfig::SshConfigFile::user().hosts() {
+        if let Some(host) = hosts.get(server) {
+            return host.hostname.clone();
+        }
+    }
+    sshconfig::get_ip(server).unwrap_or_else(|_| server.to_string())
+}
+
+/// print the last `limit` syncs, optionally only of a project and/or a server
+pub fn history(project_name: Option<&str>, server: Option<&str>, limit: usize) -> Result<()> {
+    let hostname = server.map(resolve_hostname);
+    let records = history::query(project_name, hostname.as_ref().map(|h| h.as_str()))?;
+    let records = &records[records.len().saturating_sub(limit)..];
+    if output::is_json() {
+        for record in records {
+            output::emit(&output::Event::History(record));
+        }
+    } else {
+        history::print(records);
+    }
+    Ok(())
+}
+
+/// compare the files on the server with src
+pub fn verify(config_path: &Path, project_name: &str, server: &str, options: &HostOptions) -> Result<()> {
+    let (project, host) = resolve(config_path, project_name, server, options)?;
+    let report = manifest::verify(&host, &project)?;
+    if output::is_json() {
+        output::emit(&output::Event::Verify(&report));
+    } else {
+        info!(
+            "{} on {}: deployed revision {}",
+            report.project,
+            report.host,
+            report.revision.as_ref().map(|r| r.as_str()).unwrap_or("unknown")
+        );
+        for path in report.drifted.iter() {
+            info!("drifted: {}", path);
+        }
+        for path in report.missing.iter() {
+            info!("missing: {}", path);
+        }
+        for path in report.extra.iter() {
+            info!("extra: {}", path);
+        }
+        for path in report.modified_remote.iter() {
+            info!("changed on the remote since the last sync: {}", path);
+        }
+    }
+    if !report.is_clean() {
+        bail!(
+            "{} drifted, {} missing and {} extra files in {} on {}",
+            report.drifted.len(),
+            report.missing.len(),
+            report.extra.len(),
+            project.dest,
+            server
+        );
+    }
+    info!("{} on {} is the same as {}", project.dest, server, project.src);
+    Ok(())
+}
+
+/// point dest/current on the server back to the previous release
+pub fn rollback(config_path: &Path, project_name: &str, server: &str, options: &HostOptions) -> Result<()> {
+    let (project, host) = resolve(config_path, project_name, server, options)?;
+    let release = release::rollback(&host, &project)?;
+    info!("{} on {} rolled back to release {}", project.name, server, release);
+    Ok(())
+}
+
+/// print the config files in the order they are merged, or the merged config
+pub fn show_config(config_path: &Path, resolved: bool) -> Result<()> {
+    if resolved {
+        print!("{}", config::LayeredConfig::load(config_path)?.render());
+    } else {
+        for (layer, path) in config::layer_files(config_path) {
+            println!("{:<8} {}", layer.to_string(), path.display());
+        }
+    }
+    Ok(())
+}
+
+/// report the problems of the config, fail if there are errors
+pub fn check_config(config_path: &Path) -> Result<()> {
+    let validation = validate::check(config_path);
+    for diagnostic in validation.diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+    let errors = validation.errors().count();
+    let warnings = validation.warnings().count();
+    if errors > 0 {
+        bail!("{} errors and {} warnings in the config", errors, warnings);
+    }
+    println!("config ok, {} warnings", warnings);
+    Ok(())
+}
+
+/// a WatchDog which reloads its settings from the config files
+fn create_watchdog(
+    config_path: &Path,
+    project_name: &str,
+    server: &str,
+    options: HostOptions,
+    sync_options: SyncOptions,
+    project: toml_parser::Project,
+    host: sshconfig::Host,
+) -> watchdog::WatchDog {
+    let mut watchdog = watchdog::WatchDog::new(project, host);
+    watchdog.history = true;
+    watchdog.config_paths = config::layer_files(config_path)
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect();
+    let config_path = config_path.to_path_buf();
+    let project_name = project_name.to_string();
+    let server = server.to_string();
+    watchdog.loader = Some(Box::new(move || {
+        let (mut project, host) = resolve(&config_path, &project_name, &server, &options)?;
+        sync_options.apply(&mut project)?;
+        Ok((project, host))
+    }));
+    watchdog
+}
+
+pub fn run(
+    config_path: &Path,
+    project_name: &str,
+    server: &str,
+    watch: bool,
+    delete: bool,
+    options: HostOptions,
+    sync_options: SyncOptions,
+) -> Result<()> {
+    let (mut project, host) = resolve(config_path, project_name, server, &options)?;
+    sync_options.apply(&mut project)?;
+    let session = session::Session::new(project, host)
+        .delete(delete)
+        .history(true);
+    let report = match session.sync() {
+        Ok(report) => report,
+        Err(e) => {
+            // a record for the failed sync too
+            match *e.kind() {
+                ErrorKind::SyncFailed(ref report) => output::emit(&output::Event::Sync(report)),
+                _ => output::emit(&output::Event::Error {
+                    project: &session.project().name,
+                    host: &session.host().hostname,
+                    message: e.to_string(),
+                }),
+            }
+            return Err(e);
+        }
+    };
+    output::emit(&output::Event::Sync(&report));
 
     //start watch
-    if watch {
-        start_watch(&project, &host, delete)?;
+    if watch && !signal::shutdown_requested() {
+        let (project, host) = session.into_parts();
+        let mut watchdog = create_watchdog(config_path, project_name, server, options, sync_options, project, host);
+        watchdog.start(delete)?;
     }
 
     Ok(())
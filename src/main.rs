@@ -13,9 +13,11 @@ extern crate slog_async;
 extern crate slog_json;
 extern crate shellexpand;
 
-use rn::run;
+use rn::{run, daemon, history, rollback, check_config, current_project, show_config, verify, HostOptions, SyncOptions};
+use rn::errors::Result;
 use rn::my_logger;
-use std::path::PathBuf;
+use rn::utils::{agent, delta, output, signal};
+use std::path::{Path, PathBuf};
 use shellexpand::tilde;
 
 fn main() {
@@ -26,18 +28,7 @@ fn main() {
     let config_path = tilde(matches.value_of("config").unwrap_or("~/bin/settings.toml"))
         .into_owned();
     let config_path = config_path.as_str();
-    let server = matches.value_of("server").unwrap();
-    let project_name = matches.value_of("project").unwrap_or("default");
-    let watch = matches.occurrences_of("watch") == 1;
-    let user = matches.value_of("user");
-    let password = matches.value_of("password");
-    let identity = matches.value_of("identity");
     let log_path = matches.value_of("log");
-    let delete = matches.occurrences_of("delete") == 1;
-    let port: Option<u16> = match matches.value_of("port") {
-        Some(p) => Some(p.parse().unwrap()),
-        None => None,
-    };
 
     debug!("config path: {:?}", config_path);
     // TODO: set log path from args, and log_level
@@ -46,41 +37,130 @@ fn main() {
         Some(path_str) => Some(PathBuf::from(path_str)),
     };
     let log_level = matches.occurrences_of("v") as i8;
-    let log = my_logger::get_global_log(log_level, log_path).unwrap();
+    let (log, log_guard) = my_logger::get_global_log(log_level, log_path).unwrap();
     // 必须明确写出这一句
-    let _guard = slog_scope::set_global_logger(log);
-    debug!(
-        "user: {:?}, password: {:?}, identity: {:?}",
-        user,
-        password,
-        identity
-    );
+    let guard = slog_scope::set_global_logger(log);
+    signal::install();
+    if let Some(format) = matches.value_of("output") {
+        output::set_format(format.parse().unwrap());
+    }
     let config_path_buf = &PathBuf::from(config_path);
 
-    if let Err(ref e) = run(
-        config_path_buf,
-        project_name,
-        server,
-        watch,
-        user,
-        password,
-        port,
-        identity,
-        delete,
-    )
-    {
-        if log_level == 0 {
+    let code = match execute(&matches, config_path_buf) {
+        Ok(()) => signal::exit_code().unwrap_or(0),
+        Err(ref e) => {
             error!("error: {}", e);
-            return;
+            if log_level > 0 {
+                for e in e.iter().skip(1) {
+                    error!("caused by: {}", e);
+                }
+                // The backtrace is not always generated. Try to run this example
+                // with `RUST_BACKTRACE=1`.
+                if let Some(backtrace) = e.backtrace() {
+                    error!("backtrace: {:?}", backtrace);
+                }
+            }
+            signal::exit_code().unwrap_or(1)
         }
-        error!("error: {}", e);
-        for e in e.iter().skip(1) {
-            error!("caused by: {}", e);
+    };
+    // process::exit skips destructors, flush the async loggers first
+    drop(guard);
+    drop(log_guard);
+    std::process::exit(code);
+}
+
+fn execute(matches: &clap::ArgMatches, config_path: &Path) -> Result<()> {
+    // the default config file is optional, the other layers may be enough
+    if matches.occurrences_of("config") > 0 && !config_path.is_file() {
+        return Err(format!("config file {:?} does not exist", config_path).into());
+    }
+    match matches.subcommand() {
+        ("daemon", Some(sub)) => daemon::run(config_path, sub.is_present("delete")),
+        ("status", Some(_)) => control("status"),
+        ("pause", Some(sub)) => control(&format!("pause {}", sub.value_of("project").unwrap())),
+        ("resume", Some(sub)) => control(&format!("resume {}", sub.value_of("project").unwrap_or(""))),
+        ("sync-now", Some(sub)) => control(&format!("sync-now {}", sub.value_of("project").unwrap())),
+        ("history", Some(sub)) => history(
+            sub.value_of("project"),
+            sub.value_of("host"),
+            sub.value_of("limit").unwrap().parse()?,
+        ),
+        ("verify", Some(sub)) => verify(
+            config_path,
+            &project_name(sub, config_path),
+            sub.value_of("server").unwrap(),
+            &host_options(sub)?,
+        ),
+        ("config", Some(sub)) => match sub.subcommand() {
+            ("show", Some(show)) => show_config(config_path, show.is_present("resolved")),
+            ("check", Some(_)) => check_config(config_path),
+            _ => Ok(()),
+        },
+        ("rollback", Some(sub)) => rollback(
+            config_path,
+            &project_name(sub, config_path),
+            sub.value_of("server").unwrap(),
+            &host_options(sub)?,
+        ),
+        ("agent", Some(sub)) => {
+            let stdin = std::io::stdin();
+            let stdout = std::io::stdout();
+            agent::serve(Path::new(sub.value_of("root").unwrap()), stdin.lock(), stdout.lock())
         }
-        // The backtrace is not always generated. Try to run this example
-        // with `RUST_BACKTRACE=1`.
-        if let Some(backtrace) = e.backtrace() {
-            error!("backtrace: {:?}", backtrace);
+        ("delta", Some(sub)) => {
+            let stdin = std::io::stdin();
+            let stdout = std::io::stdout();
+            delta::apply(Path::new(sub.value_of("file").unwrap()), stdin.lock(), stdout.lock())
         }
+        _ => {
+            let server = matches.value_of("server").unwrap();
+            let project_name = &project_name(matches, config_path);
+            let watch = matches.occurrences_of("watch") == 1;
+            let delete = matches.occurrences_of("delete") == 1;
+            let options = host_options(matches)?;
+            debug!(
+                "user: {:?}, password: {:?}, identity: {:?}",
+                options.user,
+                options.password,
+                options.identity
+            );
+            run(config_path, project_name, server, watch, delete, options, sync_options(matches))
+        }
+    }
+}
+
+/// the project given with -p, or the one containing the current directory
+fn project_name(matches: &clap::ArgMatches, config_path: &Path) -> String {
+    match matches.value_of("project") {
+        Some(project) => project.to_string(),
+        None => current_project(config_path).unwrap_or_else(|| "default".to_string()),
     }
 }
+
+/// the ssh settings given on the command line
+fn host_options(matches: &clap::ArgMatches) -> Result<HostOptions> {
+    Ok(HostOptions {
+        user: matches.value_of("user").map(String::from),
+        password: matches.value_of("password").map(String::from),
+        port: match matches.value_of("port") {
+            Some(p) => Some(p.parse()?),
+            None => None,
+        },
+        identity: matches.value_of("identity").map(String::from),
+    })
+}
+
+/// the files to sync and the transfer settings given on the command line
+fn sync_options(matches: &clap::ArgMatches) -> SyncOptions {
+    SyncOptions {
+        since: matches.value_of("since").map(String::from),
+        bwlimit: matches.value_of("bwlimit").map(String::from),
+    }
+}
+
+/// send a command to `rn daemon` and print its reply
+fn control(command: &str) -> Result<()> {
+    let reply = daemon::send_command(command)?;
+    print!("{}", reply);
+    Ok(())
+}
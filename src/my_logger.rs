@@ -10,7 +10,12 @@ use slog::{Level, Drain};
 
 
 
-pub fn get_global_log(log_level: i8, log_path: Option<PathBuf>) -> Result<slog::Logger> {
+/// flush the pending records and stop the async log workers when dropped
+pub struct LogGuard {
+    _guards: Vec<slog_async::AsyncGuard>,
+}
+
+pub fn get_global_log(log_level: i8, log_path: Option<PathBuf>) -> Result<(slog::Logger, LogGuard)> {
     let log_level = match log_level {
         0 => Level::Info,
         1 => Level::Debug,
@@ -19,7 +24,8 @@ pub fn get_global_log(log_level: i8, log_path: Option<PathBuf>) -> Result<slog::
 
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let console_drain = slog_async::Async::new(drain).build().fuse();
+    let (console_drain, console_guard) = slog_async::Async::new(drain).build_with_guard();
+    let console_drain = console_drain.fuse();
     let global_info =
         slog_o!("version" => "0.5",
                         "location" => slog::FnValue(move |info| {
@@ -41,15 +47,16 @@ pub fn get_global_log(log_level: i8, log_path: Option<PathBuf>) -> Result<slog::
             .add_key_value(slog_o!("type"=> "json"))
             .add_default_keys();
         let drain = builder.build().map(slog::Fuse);
-        let file_drain = slog_async::Async::new(drain).build().fuse();
+        let (file_drain, file_guard) = slog_async::Async::new(drain).build_with_guard();
+        let file_drain = file_drain.fuse();
         // join together all drains
         let drains = slog::Duplicate::new(console_drain, file_drain).fuse();
         let drains = slog::LevelFilter::new(drains, log_level).map(slog::Fuse);
         let log = slog::Logger::root(drains, global_info);
-        return Ok(log);
+        return Ok((log, LogGuard { _guards: vec![console_guard, file_guard] }));
     } else {
         let drains = slog::LevelFilter::new(console_drain, log_level).map(slog::Fuse);
         let log = slog::Logger::root(drains, global_info);
-        return Ok(log);
+        return Ok((log, LogGuard { _guards: vec![console_guard] }));
     }
 }
@@ -0,0 +1,239 @@
+use errors::*;
+use slog;
+use slog_scope;
+use std::path::Path;
+use std::sync::Arc;
+use utils::history;
+use utils::rsync::{self, SyncReport};
+use utils::sshconfig::{Host, SshConfigSource};
+use utils::toml_parser::Project;
+use utils::watchdog::{Control, Observer, WatchDog, WatchEvent};
+use super::{resolve_with, HostOptions};
+
+/// syncs a project to a host, for the tools embedding rn: nothing is read
+/// from the config files, and the logs can go to a logger of their own
+///
+/// ```no_run
+/// # use rn::session::Session;
+/// # use rn::utils::sshconfig::Host;
+/// # fn sync(project: rn::utils::toml_parser::Project) -> rn::errors::Result<()> {
+/// let report = Session::new(project, Host::local()).delete(true).sync()?;
+/// println!("{} files sent", report.files.len());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Session {
+    project: Project,
+    host: Host,
+    delete: bool,
+    history: bool,
+    output: bool,
+    signals: bool,
+    logger: Option<slog::Logger>,
+    control: Arc<Control>,
+}
+
+impl Session {
+    pub fn new(project: Project, host: Host) -> Self {
+        Session {
+            project,
+            host,
+            delete: false,
+            history: false,
+            output: false,
+            signals: false,
+            logger: None,
+            control: Arc::new(Control::default()),
+        }
+    }
+
+    /// the project and host resolved from the config file like the command
+    /// line does, with the hosts of ssh_config instead of ~/.ssh/config
+    pub fn from_config(
+        config_path: &Path,
+        project_name: &str,
+        server: &str,
+        options: &HostOptions,
+        ssh_config: &dyn SshConfigSource,
+    ) -> Result<Self> {
+        let (project, host) = resolve_with(config_path, project_name, server, options, ssh_config)?;
+        Ok(Session::new(project, host))
+    }
+
+    /// remove the files of dest which are not in src
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
+
+    /// append the syncs to the history `rn history` shows, off by default
+    pub fn history(mut self, history: bool) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// write the events of watch to the output and log its errors, as
+    /// `rn -w` does, off by default: the closure of watch gets them
+    pub fn output(mut self, output: bool) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// stop watch on SIGINT or SIGTERM and reload on SIGHUP, off by default:
+    /// the signals are the ones of the program embedding rn
+    pub fn signals(mut self, signals: bool) -> Self {
+        self.signals = signals;
+        self
+    }
+
+    /// log to this logger instead of the global one, only the logs of the
+    /// thread running the session go to it
+    pub fn logger(mut self, logger: slog::Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    pub fn project(&self) -> &Project {
+        &self.project
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    /// the project and the host, to watch with a WatchDog of its own
+    pub fn into_parts(self) -> (Project, Host) {
+        (self.project, self.host)
+    }
+
+    /// stops watch from another thread
+    pub fn control(&self) -> Arc<Control> {
+        self.control.clone()
+    }
+
+    fn scoped<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        match self.logger {
+            Some(ref logger) => slog_scope::scope(logger, f),
+            None => f(),
+        }
+    }
+
+    /// sync src to dest once
+    pub fn sync(&self) -> Result<SyncReport> {
+        self.scoped(|| {
+            let result = rsync::sync(&self.host, &self.project, self.delete);
+            if self.history {
+                history::record(&self.project, &self.host, &result);
+            }
+            result
+        })
+    }
+
+    /// sync the changes of src until on_event returns false, the control is
+    /// stopped, or SIGINT or SIGTERM is received with signals on
+    pub fn watch<F>(&self, on_event: F) -> Result<()>
+    where
+        F: FnMut(&WatchEvent) -> bool + Send + 'static,
+    {
+        let mut watchdog = WatchDog::new(self.project.clone(), self.host.clone());
+        watchdog.control = self.control.clone();
+        watchdog.history = self.history;
+        watchdog.signals = self.signals;
+        if !self.output {
+            watchdog.handlers.clear();
+        }
+        watchdog.add_handler(Observer::new(on_event, self.control.clone()));
+        self.scoped(|| watchdog.start(self.delete))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    /// keeps the messages logged
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl slog::Drain for Collect {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> ::std::result::Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_from_config() {
+        let dir = Path::new("/tmp").join(format!("rn_session_config_{}", ::std::process::id()));
+        fs::create_dir_all(dir.join("web")).unwrap();
+        let config = dir.join("rn.toml");
+        fs::write(
+            &config,
+            format!("[[projects]]\nname = \"web\"\nsrc = \"{}/web\"\ndest = \"~/web\"\n", dir.display()),
+        ).unwrap();
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            "box".to_string(),
+            Host::new("10.0.0.5", "deploy", Some("/keys/box"), None, Some(2200)),
+        );
+        let session = Session::from_config(&config, "web", "box", &HostOptions::default(), &hosts).unwrap();
+        assert_eq!(session.host().hostname, "10.0.0.5");
+        assert_eq!(session.host().identityfile, Some(PathBuf::from("/keys/box")));
+        assert_eq!(session.host().port, 2200);
+        assert_eq!(session.project().dest, "/home/deploy/web");
+        assert!(Session::from_config(&config, "web", "other", &HostOptions::default(), &hosts).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = Path::new("/tmp").join(format!("rn_session_watch_{}", ::std::process::id()));
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let src = src.canonicalize().unwrap();
+        let project: Project = ::toml::from_str(&format!(
+            "name = \"web\"\nsrc = \"{}\"\ndest = \"{}/dest/\"\n",
+            src.display(),
+            dir.display()
+        )).unwrap();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = slog::Logger::root(Collect(messages.clone()), o!());
+        let session = Session::new(project, Host::local()).logger(logger);
+
+        let control = session.control();
+        let (tx, rx) = channel();
+        let watcher = thread::spawn(move || {
+            session.watch(move |event| {
+                let _ = tx.send(event.clone());
+                // stop at the first change
+                match *event {
+                    WatchEvent::Change(_) => false,
+                    _ => true,
+                }
+            })
+        });
+        thread::sleep(Duration::from_millis(500));
+        fs::write(src.join("a.txt"), "a").unwrap();
+        let event = rx.recv_timeout(Duration::from_secs(20));
+        control.stop();
+        watcher.join().unwrap().unwrap();
+        match event {
+            Ok(WatchEvent::Change(paths)) => assert_eq!(paths, vec![src.join("a.txt")]),
+            event => panic!("unexpected {:?}", event),
+        }
+        assert!(messages.lock().unwrap().iter().any(|message| message.starts_with("stop watching")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,738 @@
+extern crate libc;
+
+use errors::*;
+use std::{env, fs};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::time::UNIX_EPOCH;
+use super::{delta, manifest, ssh};
+use super::wire::{put_bytes, put_i64, put_u32, read_frame, write_frame, Fields};
+use super::sshconfig::Host;
+use super::toml_parser::{DeployMode, Project, SourceMode};
+
+/// sent by the agent when it starts, a client only talks to the same version
+const GREETING: &str = "rn-agent 2";
+/// the larger files are sent as a delta, in frames of bounded size
+pub const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+/// both sides of a delta hold the file in memory, the larger files are
+/// left to rsync
+pub const MAX_DELTA_SIZE: u64 = 1024 * 1024 * 1024;
+/// where the agent binary is kept on the host, relative to the home directory
+const AGENT_DIR: &str = ".cache/rn";
+
+/// what the client asks the agent to do, the paths are relative to the root of the agent
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    /// replace the file, creating its directory
+    Write {
+        path: String,
+        /// the permissions of a new file
+        mode: u32,
+        /// set the permissions of an existing file too, like rsync --perms
+        perms: bool,
+        /// the modification time, in seconds and nanoseconds
+        mtime: (i64, u32),
+        data: Vec<u8>,
+    },
+    /// remove a file or a directory with its content
+    Delete { path: String },
+    Rename { from: String, to: String },
+    Mkdir { path: String },
+    Chmod { path: String, mode: u32 },
+    /// the sha256 of a file, to skip sending it if it is the same
+    Hash { path: String },
+    Quit,
+    /// the delta signature of a file, empty if it does not exist
+    Signature { path: String },
+    /// replace the file with the old one patched by the delta frames
+    /// following the request, like Write
+    Patch {
+        path: String,
+        mode: u32,
+        perms: bool,
+        mtime: (i64, u32),
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Ok,
+    /// the hex encoded sha256, None if the file does not exist
+    Hash(Option<String>),
+    Error(String),
+    /// an encoded `delta::Signature`
+    Signature(Vec<u8>),
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match *self {
+            Request::Write { ref path, mode, perms, mtime, ref data } => {
+                buf.push(1);
+                put_bytes(&mut buf, path.as_bytes());
+                put_u32(&mut buf, mode);
+                buf.push(perms as u8);
+                put_i64(&mut buf, mtime.0);
+                put_u32(&mut buf, mtime.1);
+                put_bytes(&mut buf, data);
+            }
+            Request::Delete { ref path } => {
+                buf.push(2);
+                put_bytes(&mut buf, path.as_bytes());
+            }
+            Request::Rename { ref from, ref to } => {
+                buf.push(3);
+                put_bytes(&mut buf, from.as_bytes());
+                put_bytes(&mut buf, to.as_bytes());
+            }
+            Request::Mkdir { ref path } => {
+                buf.push(4);
+                put_bytes(&mut buf, path.as_bytes());
+            }
+            Request::Chmod { ref path, mode } => {
+                buf.push(5);
+                put_bytes(&mut buf, path.as_bytes());
+                put_u32(&mut buf, mode);
+            }
+            Request::Hash { ref path } => {
+                buf.push(6);
+                put_bytes(&mut buf, path.as_bytes());
+            }
+            Request::Quit => buf.push(7),
+            Request::Signature { ref path } => {
+                buf.push(8);
+                put_bytes(&mut buf, path.as_bytes());
+            }
+            Request::Patch { ref path, mode, perms, mtime } => {
+                buf.push(9);
+                put_bytes(&mut buf, path.as_bytes());
+                put_u32(&mut buf, mode);
+                buf.push(perms as u8);
+                put_i64(&mut buf, mtime.0);
+                put_u32(&mut buf, mtime.1);
+            }
+        }
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Request> {
+        let mut fields = Fields::new(payload);
+        let request = match fields.u8()? {
+            1 => Request::Write {
+                path: fields.string()?,
+                mode: fields.u32()?,
+                perms: fields.u8()? != 0,
+                mtime: (fields.i64()?, fields.u32()?),
+                data: fields.bytes()?,
+            },
+            2 => Request::Delete { path: fields.string()? },
+            3 => Request::Rename {
+                from: fields.string()?,
+                to: fields.string()?,
+            },
+            4 => Request::Mkdir { path: fields.string()? },
+            5 => Request::Chmod {
+                path: fields.string()?,
+                mode: fields.u32()?,
+            },
+            6 => Request::Hash { path: fields.string()? },
+            7 => Request::Quit,
+            8 => Request::Signature { path: fields.string()? },
+            9 => Request::Patch {
+                path: fields.string()?,
+                mode: fields.u32()?,
+                perms: fields.u8()? != 0,
+                mtime: (fields.i64()?, fields.u32()?),
+            },
+            op => bail!("unknown request {}", op),
+        };
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match *self {
+            Response::Ok => buf.push(0),
+            Response::Hash(ref hash) => {
+                buf.push(1);
+                match *hash {
+                    Some(ref hash) => {
+                        buf.push(1);
+                        put_bytes(&mut buf, hash.as_bytes());
+                    }
+                    None => buf.push(0),
+                }
+            }
+            Response::Error(ref message) => {
+                buf.push(2);
+                put_bytes(&mut buf, message.as_bytes());
+            }
+            Response::Signature(ref signature) => {
+                buf.push(3);
+                put_bytes(&mut buf, signature);
+            }
+        }
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Response> {
+        let mut fields = Fields::new(payload);
+        let response = match fields.u8()? {
+            0 => Response::Ok,
+            1 => Response::Hash(match fields.u8()? {
+                0 => None,
+                _ => Some(fields.string()?),
+            }),
+            2 => Response::Error(fields.string()?),
+            3 => Response::Signature(fields.bytes()?),
+            status => bail!("unknown response {}", status),
+        };
+        Ok(response)
+    }
+}
+
+/// the path under root, a path which could get out of it is refused
+fn under(root: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    if path.is_empty() || relative.components().any(|c| match c {
+        Component::Normal(_) | Component::CurDir => false,
+        _ => true,
+    }) {
+        bail!("invalid path {:?}", path);
+    }
+    Ok(root.join(relative))
+}
+
+fn set_mtime(file: &fs::File, mtime: (i64, u32)) -> io::Result<()> {
+    let time = libc::timespec {
+        tv_sec: mtime.0 as libc::time_t,
+        tv_nsec: mtime.1 as libc::c_long,
+    };
+    let times = [time, time];
+    if unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// write to a temporary file next to the target, then rename it over the
+/// target, a reader never sees a partial file
+fn write_file<F>(target: &Path, mode: u32, perms: bool, mtime: (i64, u32), write: F) -> Result<()>
+where
+    F: FnOnce(&mut fs::File) -> Result<()>,
+{
+    let dir = match target.parent() {
+        Some(dir) => dir,
+        None => bail!("invalid path {:?}", target),
+    };
+    fs::create_dir_all(dir)?;
+    let name = target.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp = dir.join(format!(".{}.rn-tmp", name));
+    let result = (|| -> Result<()> {
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(&temp)?;
+        write(&mut file)?;
+        // an existing file keeps its permissions, like rsync does without --perms
+        // and a new one the mode given to open, masked by the umask
+        match fs::metadata(target) {
+            _ if perms => file.set_permissions(fs::Permissions::from_mode(mode))?,
+            Ok(metadata) => file.set_permissions(metadata.permissions())?,
+            Err(_) => {}
+        }
+        set_mtime(&file, mtime)?;
+        fs::rename(&temp, target)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn handle(root: &Path, request: &Request) -> Result<Response> {
+    match *request {
+        Request::Write { ref path, mode, perms, mtime, ref data } => {
+            write_file(&under(root, path)?, mode, perms, mtime, |file| Ok(file.write_all(data)?))?
+        }
+        Request::Delete { ref path } => {
+            let path = under(root, path)?;
+            match fs::symlink_metadata(&path) {
+                Ok(ref metadata) if metadata.is_dir() => fs::remove_dir_all(&path)?,
+                Ok(_) => fs::remove_file(&path)?,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Request::Rename { ref from, ref to } => {
+            let to = under(root, to)?;
+            if let Some(dir) = to.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::rename(under(root, from)?, to)?;
+        }
+        Request::Mkdir { ref path } => fs::create_dir_all(under(root, path)?)?,
+        Request::Chmod { ref path, mode } => fs::set_permissions(under(root, path)?, fs::Permissions::from_mode(mode))?,
+        Request::Hash { ref path } => {
+            let path = under(root, path)?;
+            return Ok(Response::Hash(if path.is_file() {
+                Some(manifest::hash_file(&path)?)
+            } else {
+                None
+            }));
+        }
+        Request::Signature { ref path } => {
+            let old = read_old(&under(root, path)?)?;
+            let signature = delta::signature(&old, delta::block_size_for(old.len() as u64));
+            return Ok(Response::Signature(signature.encode()));
+        }
+        Request::Quit => {}
+        Request::Patch { .. } => bail!("no delta for {:?}", request),
+    }
+    Ok(Response::Ok)
+}
+
+/// the content of a file, empty if it does not exist
+fn read_old(path: &Path) -> Result<Vec<u8>> {
+    match fs::read(path) {
+        Ok(old) => Ok(old),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// apply the delta frames following a Patch request, they are all read
+/// even if the file can not be written
+fn patch_file<R: Read>(root: &Path, request: &Request, input: &mut R) -> Result<()> {
+    let mut read = false;
+    let result = match *request {
+        Request::Patch { ref path, mode, perms, mtime } => under(root, path).and_then(|target| {
+            let old = read_old(&target)?;
+            write_file(&target, mode, perms, mtime, |file| {
+                read = true;
+                delta::read_delta(&old, input, file)
+            })
+        }),
+        _ => Err(format!("{:?} is not a patch", request).into()),
+    };
+    if !read {
+        delta::skip_delta(input)?;
+    }
+    result
+}
+
+/// the agent: apply the requests read from input under root until the end
+/// of the input or Quit, the errors are sent back to the client
+pub fn serve<R: Read, W: Write>(root: &Path, mut input: R, mut output: W) -> Result<()> {
+    fs::create_dir_all(root)?;
+    write_frame(&mut output, GREETING.as_bytes())?;
+    while let Some(payload) = read_frame(&mut input)? {
+        let request = Request::decode(&payload)?;
+        let result = match request {
+            Request::Patch { .. } => patch_file(root, &request, &mut input).map(|_| Response::Ok),
+            _ => handle(root, &request),
+        };
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => Response::Error(e.to_string()),
+        };
+        write_frame(&mut output, &response.encode())?;
+        if request == Request::Quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// whether the changes of the project can be pushed by an agent, the
+/// others need rsync to select or place the files
+pub fn can_push(project: &Project) -> bool {
+    let transfer = project.transfer.clone().unwrap_or_default();
+    project.deploy_mode != Some(DeployMode::Release) && project.source != Some(SourceMode::GitTracked)
+        && project.gitignore != Some(true) && project.filter_file.is_none() && project.since.is_none()
+        && project.manifest != Some(true)
+        && project.rsync_args.as_ref().map_or(true, |args| args.is_empty())
+        && transfer.chmod.is_none() && transfer.chown.is_none()
+        && transfer.owner != Some(true) && transfer.group != Some(true)
+        && transfer.xattrs != Some(true) && transfer.acls != Some(true)
+        && transfer.bwlimit.is_none()
+}
+
+/// the name of the system as `uname -sm` prints it, to know whether this
+/// binary runs on a host
+fn system() -> (&'static str, &'static str) {
+    let os = match env::consts::OS {
+        "linux" => "Linux",
+        "macos" => "Darwin",
+        "freebsd" => "FreeBSD",
+        os => os,
+    };
+    let arch = match (env::consts::OS, env::consts::ARCH) {
+        ("macos", "aarch64") => "arm64",
+        ("freebsd", "x86_64") => "amd64",
+        (_, arch) => arch,
+    };
+    (os, arch)
+}
+
+/// copy this binary to the host unless it is already there, returns its
+/// path relative to the remote home directory
+pub fn deploy(host: &Host) -> Result<String> {
+    let uname = ssh::exec(host, "uname -sm", None)?;
+    let (os, arch) = system();
+    if uname.split_whitespace().collect::<Vec<_>>() != vec![os, arch] {
+        bail!("the host runs {}, rn is built for {} {}", uname.trim(), os, arch);
+    }
+    let exe = env::current_exe()?;
+    let hash = manifest::hash_file(&exe)?;
+    let path = format!("{}/rn-agent-{}-{}", AGENT_DIR, env!("CARGO_PKG_VERSION"), &hash[..16]);
+    let check = format!("test -x {} && echo ok || true", ssh::quote(&path));
+    if ssh::exec(host, &check, None)?.trim() != "ok" {
+        info!("copy the agent to {}:{}", host.hostname, path);
+        let upload = format!(
+            "mkdir -p {dir} && cat > {path}.tmp && chmod 755 {path}.tmp && mv -f {path}.tmp {path}",
+            dir = AGENT_DIR,
+            path = ssh::quote(&path)
+        );
+        ssh::exec(host, &upload, Some(&fs::read(&exe)?))?;
+    }
+    Ok(path)
+}
+
+/// a connection to an agent
+pub struct Agent {
+    reader: Box<dyn Read + Send>,
+    writer: Box<dyn Write + Send>,
+    /// the ssh running the agent on the host
+    child: Option<Child>,
+    /// set when the connection failed, the agent can not be used anymore
+    broken: bool,
+}
+
+impl Agent {
+    /// talk to an agent over a pipe, it must greet first
+    pub fn new<R, W>(reader: R, writer: W) -> Result<Agent>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let mut agent = Agent {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            child: None,
+            broken: false,
+        };
+        match read_frame(&mut agent.reader)? {
+            Some(ref greeting) if greeting.as_slice() == GREETING.as_bytes() => Ok(agent),
+            Some(greeting) => bail!("unexpected agent greeting {:?}", String::from_utf8_lossy(&greeting)),
+            None => bail!("the agent exited before greeting"),
+        }
+    }
+
+    /// start an agent in dest of the project on the host, copying it there first
+    pub fn start(host: &Host, project: &Project) -> Result<Agent> {
+        let path = if host.is_local() {
+            env::current_exe()?.to_string_lossy().into_owned()
+        } else {
+            deploy(host)?
+        };
+        let remote_command = format!("{} agent {}", ssh::quote(&path), ssh::quote(&project.dest));
+        let mut child = ssh::command(host, &remote_command)?
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            _ => bail!("no pipe to the agent"),
+        };
+        let mut agent = match Agent::new(stdout, stdin) {
+            Ok(agent) => agent,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e);
+            }
+        };
+        agent.child = Some(child);
+        info!("{}: agent started on {}", project.name, host.hostname);
+        Ok(agent)
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    fn call(&mut self, request: &Request) -> Result<Response> {
+        self.call_with(request, |_| Ok(()))
+    }
+
+    /// send the request and the frames written by after it, then read the response
+    fn call_with<F>(&mut self, request: &Request, after: F) -> Result<Response>
+    where
+        F: FnOnce(&mut Box<dyn Write + Send>) -> io::Result<()>,
+    {
+        let result = write_frame(&mut self.writer, &request.encode())
+            .and_then(|_| after(&mut self.writer))
+            .map_err(Error::from)
+            .and_then(|_| read_frame(&mut self.reader))
+            .and_then(|payload| match payload {
+                Some(payload) => Response::decode(&payload),
+                None => bail!("the agent exited"),
+            });
+        match result {
+            Ok(Response::Error(message)) => bail!("agent: {}", message),
+            Ok(response) => Ok(response),
+            Err(e) => {
+                self.broken = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// send a file unless the remote one has the same content, only its
+    /// changes if it exists or is large. Returns the bytes sent; with perms
+    /// an existing file gets the mode of the local one
+    pub fn send(&mut self, path: &str, local: &Path, perms: bool) -> Result<u64> {
+        let metadata = fs::metadata(local)?;
+        if metadata.len() > MAX_DELTA_SIZE {
+            bail!("{} is too large for the agent", path);
+        }
+        let remote = self.hash(path)?;
+        if let Some(ref hash) = remote {
+            if *hash == manifest::hash_file(local)? {
+                return Ok(0);
+            }
+        }
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mtime = (mtime.as_secs() as i64, mtime.subsec_nanos());
+        let mode = metadata.permissions().mode() & 0o7777;
+        if remote.is_some() || metadata.len() > MAX_FILE_SIZE {
+            return self.send_delta(path, local, mode, perms, mtime);
+        }
+        let data = fs::read(local)?;
+        let bytes = data.len() as u64;
+        self.call(&Request::Write {
+            path: path.to_string(),
+            mode,
+            perms,
+            mtime,
+            data,
+        })?;
+        Ok(bytes)
+    }
+
+    /// send the changes of a file against the signature of the remote one
+    fn send_delta(&mut self, path: &str, local: &Path, mode: u32, perms: bool, mtime: (i64, u32)) -> Result<u64> {
+        let signature = match self.call(&Request::Signature { path: path.to_string() })? {
+            Response::Signature(ref signature) => delta::Signature::decode(signature)?,
+            response => bail!("unexpected agent response {:?}", response),
+        };
+        let changes = delta::delta(&signature, &fs::read(local)?);
+        let request = Request::Patch {
+            path: path.to_string(),
+            mode,
+            perms,
+            mtime,
+        };
+        self.call_with(&request, |writer| delta::write_delta(&changes, writer))?;
+        Ok(changes.data_length())
+    }
+
+    pub fn delete(&mut self, path: &str) -> Result<()> {
+        self.call(&Request::Delete { path: path.to_string() }).map(|_| ())
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.call(&Request::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        }).map(|_| ())
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> Result<()> {
+        self.call(&Request::Mkdir { path: path.to_string() }).map(|_| ())
+    }
+
+    pub fn chmod(&mut self, path: &str, mode: u32) -> Result<()> {
+        self.call(&Request::Chmod {
+            path: path.to_string(),
+            mode,
+        }).map(|_| ())
+    }
+
+    /// the sha256 of the remote file
+    pub fn hash(&mut self, path: &str) -> Result<Option<String>> {
+        match self.call(&Request::Hash { path: path.to_string() })? {
+            Response::Hash(hash) => Ok(hash),
+            response => bail!("unexpected agent response {:?}", response),
+        }
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        if !self.broken {
+            let _ = self.call(&Request::Quit);
+        }
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::toml_parser::GlobalConfig;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn signature_of(old: &[u8]) -> delta::Signature {
+        delta::signature(old, delta::block_size_for(old.len() as u64))
+    }
+
+    #[test]
+    fn test_encode() {
+        let requests = vec![
+            Request::Write {
+                path: "a/b.txt".to_string(),
+                mode: 0o644,
+                perms: true,
+                mtime: (1_500_000_000, 42),
+                data: b"hello".to_vec(),
+            },
+            Request::Rename {
+                from: "a".to_string(),
+                to: "b".to_string(),
+            },
+            Request::Patch {
+                path: "a/b.txt".to_string(),
+                mode: 0o600,
+                perms: false,
+                mtime: (1_500_000_000, 0),
+            },
+            Request::Quit,
+        ];
+        for request in requests {
+            assert_eq!(Request::decode(&request.encode()).unwrap(), request);
+        }
+        let response = Response::Hash(Some("ab".to_string()));
+        assert_eq!(Response::decode(&response.encode()).unwrap(), response);
+        assert!(Request::decode(&[1, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_can_push() {
+        let project = |settings: &str| -> Project {
+            ::toml::from_str(&format!("name = \"web\"\nsrc = \"/tmp\"\ndest = \"/srv/web\"\n{}", settings)).unwrap()
+        };
+        assert!(can_push(&project("")));
+        assert!(can_push(&project("rsync_args = []")));
+        assert!(can_push(&project("[transfer]\nperms = true\nchecksum = true")));
+        // compression does not change what the agent writes
+        assert!(can_push(&project("[transfer]\ncompress = true")));
+        let settings = [
+            "rsync_args = [\"--inplace\"]",
+            "[transfer]\nchmod = \"F644\"",
+            "[transfer]\nchown = \"www-data:www-data\"",
+            "[transfer]\nowner = true",
+            "[transfer]\ngroup = true",
+            "[transfer]\nxattrs = true",
+            "[transfer]\nacls = true",
+            "[transfer]\nbwlimit = \"1m\"",
+        ];
+        for settings in settings.iter() {
+            assert!(!can_push(&project(settings)), "{}", settings);
+        }
+
+        // the wan profile of a public address compresses
+        let config: GlobalConfig = ::toml::from_str("").unwrap();
+        let mut web = project("");
+        assert_eq!(config.apply_host(&mut web, "example.com", "93.184.216.34").unwrap(), "wan");
+        assert_eq!(web.transfer.as_ref().unwrap().compress, Some(true));
+        assert!(can_push(&web));
+    }
+
+    #[test]
+    fn test_under() {
+        let root = Path::new("/srv/web");
+        assert_eq!(under(root, "a/./b").unwrap(), Path::new("/srv/web/a/b"));
+        assert!(under(root, "../etc/passwd").is_err());
+        assert!(under(root, "/etc/passwd").is_err());
+        assert!(under(root, "").is_err());
+    }
+
+    #[test]
+    fn test_agent() {
+        let dir = env::temp_dir().join(format!("rn_agent_{}", std::process::id()));
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), "hello").unwrap();
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let root = dest.clone();
+        let agent_thread = thread::spawn(move || serve(&root, server.try_clone().unwrap(), server));
+        let mut agent = Agent::new(client.try_clone().unwrap(), client).unwrap();
+
+        assert_eq!(agent.send("web/a.txt", &src.join("a.txt"), false).unwrap(), 5);
+        assert_eq!(fs::read_to_string(dest.join("web/a.txt")).unwrap(), "hello");
+        let modified = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+        assert_eq!(modified(&dest.join("web/a.txt")), modified(&src.join("a.txt")));
+        // the same content is not sent again
+        assert_eq!(agent.send("web/a.txt", &src.join("a.txt"), false).unwrap(), 0);
+        assert!(agent.hash("web/a.txt").unwrap().is_some());
+
+        agent.rename("web/a.txt", "b.txt").unwrap();
+        agent.chmod("b.txt", 0o600).unwrap();
+        assert_eq!(fs::metadata(dest.join("b.txt")).unwrap().permissions().mode() & 0o777, 0o600);
+        agent.mkdir("empty/dir").unwrap();
+        assert!(dest.join("empty/dir").is_dir());
+        agent.delete("empty").unwrap();
+        assert!(!dest.join("empty").exists());
+        assert!(agent.delete("../src").is_err());
+        assert!(!agent.is_broken());
+
+        // b.txt keeps its mode unless perms is set
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        fs::write(src.join("b.txt"), "changed").unwrap();
+        fs::set_permissions(src.join("b.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+        agent.send("b.txt", &src.join("b.txt"), false).unwrap();
+        assert_eq!(mode(&dest.join("b.txt")), 0o600);
+        fs::write(src.join("b.txt"), "changed again").unwrap();
+        agent.send("b.txt", &src.join("b.txt"), true).unwrap();
+        assert_eq!(mode(&dest.join("b.txt")), 0o640);
+
+        // an existing file only gets the changed blocks
+        let old: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(src.join("big.bin"), &old).unwrap();
+        assert_eq!(agent.send("big.bin", &src.join("big.bin"), false).unwrap(), old.len() as u64);
+        let mut new = old.clone();
+        new.extend_from_slice(b"appended");
+        fs::write(src.join("big.bin"), &new).unwrap();
+        let sent = agent.send("big.bin", &src.join("big.bin"), false).unwrap();
+        assert!(sent < 2 * delta::block_size_for(old.len() as u64) as u64, "{}", sent);
+        assert_eq!(fs::read(dest.join("big.bin")).unwrap(), new);
+        assert_eq!(modified(&dest.join("big.bin")), modified(&src.join("big.bin")));
+        // a patch which can not be applied does not break the connection
+        let outside = Request::Patch {
+            path: "../x".to_string(),
+            mode: 0o644,
+            perms: false,
+            mtime: (0, 0),
+        };
+        let changes = delta::delta(&signature_of(&[]), b"x");
+        assert!(agent.call_with(&outside, |writer| delta::write_delta(&changes, writer)).is_err());
+        assert!(!agent.is_broken());
+        assert!(agent.hash("big.bin").unwrap().is_some());
+
+        drop(agent);
+        agent_thread.join().unwrap().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,431 @@
+use errors::*;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use toml::Value;
+use toml::value::Table;
+use super::toml_parser::{resolve_extends, GlobalConfig, GLOBAL_KEYS};
+use super::util::{config_dir, load_file};
+
+/// the config shipped with a repo, found by walking up from the current directory
+pub const PROJECT_FILE: &str = ".rn.toml";
+const SYSTEM_FILE: &str = "/etc/rn/config.toml";
+/// the env vars with this prefix followed by a key of the config override it,
+/// e.g. RN_GLOBAL_USER or RN_HOSTS
+const ENV_PREFIX: &str = "RN_";
+
+/// where a setting comes from, the later layers override the earlier ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+    /// /etc/rn/config.toml
+    System,
+    /// $XDG_CONFIG_HOME/rn/config.toml
+    User,
+    /// --config, ~/bin/settings.toml by default
+    File,
+    /// .rn.toml
+    Project,
+    /// RN_* env vars
+    Env,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Layer::System => "system",
+            Layer::User => "user",
+            Layer::File => "file",
+            Layer::Project => "project",
+            Layer::Env => "env",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// the layer and the file or env var a setting comes from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub layer: Layer,
+    pub origin: String,
+}
+
+/// the config merged from all the layers
+#[derive(Debug, Clone)]
+pub struct LayeredConfig {
+    pub value: Value,
+    /// "global_user", "projects.default.src", ... -> where it comes from
+    pub sources: BTreeMap<String, Source>,
+    /// the files which were merged, in order
+    pub files: Vec<(Layer, PathBuf)>,
+}
+
+/// the closest .rn.toml in dir or its parents
+pub fn find_project_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// the config files which exist, in the order they are merged
+pub fn layer_files(config_path: &Path) -> Vec<(Layer, PathBuf)> {
+    let mut files = vec![
+        (Layer::System, PathBuf::from(SYSTEM_FILE)),
+        (Layer::User, config_dir().join("config.toml")),
+        (Layer::File, config_path.to_path_buf()),
+    ];
+    if let Some(path) = env::current_dir().ok().and_then(|dir| find_project_file(&dir)) {
+        files.push((Layer::Project, path));
+    }
+    files.retain(|&(_, ref path)| path.is_file());
+    files
+}
+
+/// the name of an element in an array of tables, used to merge the projects
+fn element_id(value: &Value, index: usize) -> String {
+    match value.get("name").and_then(|name| name.as_str()) {
+        Some(name) => name.to_string(),
+        None => index.to_string(),
+    }
+}
+
+fn is_named_tables(array: &[Value]) -> bool {
+    !array.is_empty() && array.iter().all(|v| v.get("name").map_or(false, |n| n.is_str()))
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+/// record the source of every setting in value
+fn mark(prefix: &str, value: &Value, source: &Source, sources: &mut BTreeMap<String, Source>) {
+    // a replaced table must not keep the sources of its old keys
+    let stale: Vec<String> = sources
+        .keys()
+        .filter(|key| key.starts_with(&format!("{}.", prefix)))
+        .cloned()
+        .collect();
+    for key in stale {
+        sources.remove(&key);
+    }
+    match *value {
+        Value::Table(ref table) => {
+            for (key, value) in table.iter() {
+                mark(&join_key(prefix, key), value, source, sources);
+            }
+        }
+        Value::Array(ref array) if is_named_tables(array) => {
+            for (index, element) in array.iter().enumerate() {
+                mark(&join_key(prefix, &element_id(element, index)), element, source, sources);
+            }
+        }
+        _ => {
+            sources.insert(prefix.to_string(), source.clone());
+        }
+    }
+}
+
+/// merge over into base: tables key by key, arrays of named tables
+/// (the projects) element by element, anything else is replaced
+fn merge(prefix: &str, base: &mut Table, over: Table, source: &Source, sources: &mut BTreeMap<String, Source>) {
+    for (key, value) in over {
+        let path = join_key(prefix, &key);
+        match (base.get_mut(&key), value) {
+            (Some(&mut Value::Table(ref mut base)), Value::Table(over)) => {
+                merge(&path, base, over, source, sources);
+            }
+            (Some(&mut Value::Array(ref mut base)), Value::Array(over))
+                if is_named_tables(base) && is_named_tables(&over) =>
+            {
+                for element in over {
+                    let id = element_id(&element, 0);
+                    let element_path = join_key(&path, &id);
+                    match base.iter_mut().find(|e| element_id(e, 0) == id) {
+                        Some(&mut Value::Table(ref mut base)) => {
+                            if let Value::Table(over) = element {
+                                merge(&element_path, base, over, source, sources);
+                            }
+                        }
+                        _ => {
+                            mark(&element_path, &element, source, sources);
+                            base.push(element);
+                        }
+                    }
+                }
+            }
+            (_, value) => {
+                mark(&path, &value, source, sources);
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// make the relative src of the projects in a .rn.toml relative to its directory
+fn resolve_project_src(value: &mut Value, dir: &Path) {
+    let projects = match value.get_mut("projects").and_then(|p| p.as_array_mut()) {
+        Some(projects) => projects,
+        None => return,
+    };
+    for project in projects.iter_mut() {
+        let src = match project.get("src").and_then(|src| src.as_str()) {
+            Some(src) if !src.starts_with('~') && Path::new(src).is_relative() => {
+                // "." and "./" both sync the content of dir, like "dir/"
+                let src = src.trim_start_matches("./");
+                dir.join(if src == "." { "" } else { src })
+            }
+            _ => continue,
+        };
+        if let Some(table) = project.as_table_mut() {
+            table.insert("src".to_string(), Value::String(src.to_string_lossy().into_owned()));
+        }
+    }
+}
+
+/// the value of a RN_* env var: arrays and tables are written as toml, ports
+/// are numbers
+fn env_value(key: &str, raw: &str) -> Value {
+    if raw.trim_start().starts_with('[') || raw.trim_start().starts_with('{') {
+        if let Ok(Value::Table(mut table)) = format!("v = {}", raw).parse::<Value>() {
+            if let Some(value) = table.remove("v") {
+                return value;
+            }
+        }
+    }
+    if key.ends_with("_port") {
+        if let Ok(port) = raw.trim().parse::<i64>() {
+            return Value::Integer(port);
+        }
+    }
+    Value::String(raw.to_string())
+}
+
+impl LayeredConfig {
+    /// merge all the layers, config_path is the file given by --config
+    pub fn load(config_path: &Path) -> Result<Self> {
+        let files = layer_files(config_path);
+        if files.is_empty() {
+            bail!(
+                "no config file found, create {:?}, {:?} or a {} in the project",
+                config_path,
+                config_dir().join("config.toml"),
+                PROJECT_FILE
+            );
+        }
+        let vars: Vec<(String, String)> = env::vars().collect();
+        Self::load_from(files, &vars)
+    }
+
+    pub fn load_from(files: Vec<(Layer, PathBuf)>, vars: &[(String, String)]) -> Result<Self> {
+        let mut config = LayeredConfig {
+            value: Value::Table(Table::new()),
+            sources: BTreeMap::new(),
+            files: Vec::new(),
+        };
+        for (layer, path) in files {
+            let text = load_file(&path)?;
+            let mut value: Value = text.parse().chain_err(|| format!("parse {:?} failed", path))?;
+            if layer == Layer::Project {
+                if let Some(dir) = path.parent() {
+                    resolve_project_src(&mut value, dir);
+                }
+            }
+            let source = Source {
+                layer,
+                origin: path.to_string_lossy().into_owned(),
+            };
+            config.merge(value, &source);
+            debug!("config layer {}: {:?}", layer, path);
+            config.files.push((layer, path));
+        }
+        for &(ref name, ref raw) in vars.iter() {
+            if !name.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            // the other RN_* vars are not settings, e.g. RN_LOG
+            let key = name[ENV_PREFIX.len()..].to_lowercase();
+            if !GLOBAL_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            let mut table = Table::new();
+            let value = env_value(&key, raw);
+            table.insert(key, value);
+            let source = Source {
+                layer: Layer::Env,
+                origin: name.clone(),
+            };
+            config.merge(Value::Table(table), &source);
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, value: Value, source: &Source) {
+        let over = match value {
+            Value::Table(table) => table,
+            _ => return,
+        };
+        if let Value::Table(ref mut base) = self.value {
+            merge("", base, over, source, &mut self.sources);
+        }
+    }
+
+    pub fn config(&self) -> Result<GlobalConfig> {
+        let mut value = self.value.clone();
+        resolve_extends(&mut value)?;
+        let config: GlobalConfig = value
+            .try_into()
+            .chain_err(|| format!("invalid config merged from {:?}", self.files))?;
+        Ok(config)
+    }
+
+    fn render_entry(&self, path: &str, key: &str, value: &Value, text: &mut String) {
+        // keep the passwords out of the terminal
+        let shown = if key.contains("password") {
+            "\"********\"".to_string()
+        } else {
+            value.to_string()
+        };
+        let line = format!("{} = {}", key, shown);
+        match self.sources.get(path) {
+            Some(source) => text.push_str(&format!("{:<48} # {} {}\n", line, source.layer, source.origin)),
+            None => text.push_str(&format!("{}\n", line)),
+        }
+    }
+
+    fn render_table(&self, prefix: &str, header: &str, table: &Table, text: &mut String) {
+        let is_section = |value: &Value| value.is_table() || value.as_array().map_or(false, |a| is_named_tables(a));
+        for (key, value) in table.iter().filter(|&(_, v)| !is_section(v)) {
+            self.render_entry(&join_key(prefix, key), key, value, text);
+        }
+        for (key, value) in table.iter().filter(|&(_, v)| is_section(v)) {
+            let path = join_key(prefix, key);
+            let name = join_key(header, key);
+            match *value {
+                Value::Table(ref table) => {
+                    text.push_str(&format!("\n[{}]\n", name));
+                    self.render_table(&path, &name, table, text);
+                }
+                Value::Array(ref array) => {
+                    for (index, element) in array.iter().enumerate() {
+                        text.push_str(&format!("\n[[{}]]\n", name));
+                        if let Some(table) = element.as_table() {
+                            self.render_table(&join_key(&path, &element_id(element, index)), &name, table, text);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// the merged config as toml, each setting commented with where it comes from
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for &(layer, ref path) in self.files.iter() {
+            text.push_str(&format!("# {}: {}\n", layer, path.display()));
+        }
+        text.push('\n');
+        if let Value::Table(ref table) = self.value {
+            self.render_table("", "", table, &mut text);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_layers() {
+        let dir = Path::new("/tmp").join(format!("rn_config_{}", process::id()));
+        fs::create_dir_all(dir.join("repo/sub")).unwrap();
+        let user = write(
+            &dir,
+            "config.toml",
+            r#"global_user = "root"
+global_dest_root = "/root"
+
+[[projects]]
+name = "default"
+src = "/home/a"
+dest = "/root/a"
+exclude = [".git"]
+
+[[projects]]
+name = "other"
+src = "/home/b"
+dest = "/root/b"
+"#,
+        );
+        let project = write(
+            &dir.join("repo"),
+            PROJECT_FILE,
+            r#"[[projects]]
+name = "default"
+src = "."
+exclude = ["target"]
+"#,
+        );
+        assert_eq!(find_project_file(&dir.join("repo/sub")), Some(project.clone()));
+
+        let vars = vec![
+            ("RN_GLOBAL_USER".to_string(), "ubuntu".to_string()),
+            ("RN_GLOBAL_PORT".to_string(), "2222".to_string()),
+            ("RN_LOG".to_string(), "debug".to_string()),
+            ("RN_HOSTS".to_string(), "{ box = { compress = true } }".to_string()),
+            (
+                "RN_PROJECTS".to_string(),
+                r#"[{ name = "other", dest = "/srv/b" }]"#.to_string(),
+            ),
+        ];
+        let config = LayeredConfig::load_from(vec![(Layer::User, user), (Layer::Project, project)], &vars).unwrap();
+        let global = config.config().unwrap();
+        assert_eq!(global.global_user, Some("ubuntu".to_string()));
+        assert_eq!(global.global_port, Some(2222));
+        let projects = global.projects.unwrap();
+        assert_eq!(projects.len(), 2);
+        assert_eq!(projects[0].src, format!("{}/", dir.join("repo").display()));
+        assert_eq!(projects[0].dest, "/root/a");
+        assert_eq!(projects[0].exclude, Some(vec!["target".to_string()]));
+        assert_eq!(projects[1].src, "/home/b");
+        assert_eq!(projects[1].dest, "/srv/b");
+        assert_eq!(global.hosts.unwrap()["box"].compress, Some(true));
+
+        assert_eq!(config.sources["global_user"].layer, Layer::Env);
+        assert_eq!(config.sources["global_dest_root"].layer, Layer::User);
+        assert_eq!(config.sources["projects.default.src"].layer, Layer::Project);
+        assert_eq!(config.sources["projects.default.dest"].layer, Layer::User);
+        assert!(!config.sources.contains_key("log"));
+        assert_eq!(config.sources["projects.other.dest"].origin, "RN_PROJECTS");
+
+        let text = config.render();
+        assert!(text.contains("[[projects]]"));
+        assert!(text.contains(r#"global_user = "ubuntu""#));
+        assert!(text.contains("# env RN_GLOBAL_USER"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_env_value() {
+        assert_eq!(env_value("global_user", "root"), Value::String("root".to_string()));
+        assert_eq!(env_value("global_port", "22"), Value::Integer(22));
+        assert_eq!(env_value("global_password", "1234"), Value::String("1234".to_string()));
+        assert_eq!(
+            env_value("global_exclude", r#"[".git"]"#),
+            Value::Array(vec![Value::String(".git".to_string())])
+        );
+    }
+}
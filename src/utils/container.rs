@@ -0,0 +1,510 @@
+use errors::*;
+use regex::Regex;
+use std::{fs, io};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use super::delta;
+use super::rsync::{read_in_background, SyncReport};
+use super::ssh::quote;
+use super::sshconfig::Host;
+use super::toml_parser::Project;
+use super::util::{create_re, is_exclude};
+
+/// without rsync, the smaller files are sent whole with tar, a delta saves
+/// little on them
+const DELTA_MIN_SIZE: u64 = 1024 * 1024;
+
+/// what runs the container
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Runtime {
+    Docker,
+    Podman,
+    Kubernetes,
+}
+
+impl Runtime {
+    /// the scheme of the targets, e.g. `docker://`
+    fn scheme(&self) -> &'static str {
+        match *self {
+            Runtime::Docker => "docker://",
+            Runtime::Podman => "podman://",
+            Runtime::Kubernetes => "k8s://",
+        }
+    }
+
+    /// the command line tool of the runtime
+    pub fn program(&self) -> &'static str {
+        match *self {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+            Runtime::Kubernetes => "kubectl",
+        }
+    }
+}
+
+/// a container to sync to, given as `docker://container/path`,
+/// `podman://container/path` or `k8s://namespace/pod[/container]/path`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Container {
+    pub runtime: Runtime,
+    /// the docker, podman or kubectl to run
+    pub program: String,
+    pub namespace: Option<String>,
+    /// the container, or the pod for kubernetes
+    pub name: String,
+    /// the container in the pod, the default one if not set
+    pub container: Option<String>,
+    /// the dest given in the target, over the one of the project
+    pub path: Option<String>,
+    /// whether rsync is installed in the container, None until checked
+    pub rsync: Option<bool>,
+}
+
+/// whether the server given on the command line is a container
+pub fn is_target(server: &str) -> bool {
+    [Runtime::Docker, Runtime::Podman, Runtime::Kubernetes]
+        .iter()
+        .any(|runtime| server.starts_with(runtime.scheme()))
+}
+
+fn check_name(name: &str, what: &str, target: &str) -> Result<()> {
+    let re = Regex::new(r"^[A-Za-z0-9][A-Za-z0-9_.-]*$")?;
+    if !re.is_match(name) {
+        bail!("invalid {} {:?} in {}", what, name, target);
+    }
+    Ok(())
+}
+
+/// "srv/app" -> "/srv/app", None if empty
+fn dest_path(rest: Option<&str>) -> Option<String> {
+    match rest {
+        Some(rest) if !rest.is_empty() => Some(format!("/{}", rest)),
+        _ => None,
+    }
+}
+
+/// the files of the listing of dest which are in src too, not excluded,
+/// and in files if given
+fn select_deltas<'a, I>(project: &Project, files: Option<&[String]>, listing: I) -> Vec<String>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let excludes: Vec<Regex> = project
+        .exclude
+        .iter()
+        .flat_map(|exclude| exclude.iter())
+        .filter_map(|pattern| create_re(pattern))
+        .collect();
+    let src = Path::new(&project.src);
+    listing
+        .map(|line| line.trim_start_matches("./"))
+        .filter(|relative| !relative.is_empty())
+        .filter(|relative| files.map_or(true, |files| files.iter().any(|file| file == relative)))
+        .filter(|relative| !is_exclude(&Path::new("/").join(relative), &excludes))
+        .filter(|relative| fs::symlink_metadata(src.join(relative)).map(|m| m.is_file()).unwrap_or(false))
+        .map(String::from)
+        .collect()
+}
+
+/// parse a container target, the container of a pod can be left empty to
+/// give a path: `k8s://namespace/pod//srv/app`
+pub fn parse(target: &str) -> Result<Container> {
+    let runtime = match [Runtime::Docker, Runtime::Podman, Runtime::Kubernetes]
+        .iter()
+        .find(|runtime| target.starts_with(runtime.scheme()))
+    {
+        Some(runtime) => *runtime,
+        None => bail!("{} is not a container, e.g. docker://container/path", target),
+    };
+    let rest = &target[runtime.scheme().len()..];
+    let container = if runtime == Runtime::Kubernetes {
+        let mut parts = rest.splitn(4, '/');
+        let namespace = parts.next().unwrap_or("");
+        let pod = parts.next().unwrap_or("");
+        if pod.is_empty() {
+            bail!("no pod in {}, e.g. k8s://namespace/pod/container/path", target);
+        }
+        check_name(namespace, "namespace", target)?;
+        check_name(pod, "pod", target)?;
+        let container = match parts.next() {
+            Some(container) if !container.is_empty() => {
+                check_name(container, "container", target)?;
+                Some(container.to_string())
+            }
+            _ => None,
+        };
+        Container {
+            runtime,
+            program: runtime.program().to_string(),
+            namespace: Some(namespace.to_string()),
+            name: pod.to_string(),
+            container,
+            path: dest_path(parts.next()),
+            rsync: None,
+        }
+    } else {
+        let mut parts = rest.splitn(2, '/');
+        let name = parts.next().unwrap_or("");
+        check_name(name, "container", target)?;
+        Container {
+            runtime,
+            program: runtime.program().to_string(),
+            namespace: None,
+            name: name.to_string(),
+            container: None,
+            path: dest_path(parts.next()),
+            rsync: None,
+        }
+    };
+    Ok(container)
+}
+
+impl Container {
+    /// the arguments of the program running a command in the container,
+    /// before the command
+    fn exec_args(&self, name: &str) -> Vec<String> {
+        let mut args = vec!["exec".to_string(), "-i".to_string()];
+        if let Some(ref namespace) = self.namespace {
+            args.push("-n".to_string());
+            args.push(namespace.to_string());
+        }
+        args.push(name.to_string());
+        if self.runtime == Runtime::Kubernetes {
+            if let Some(ref container) = self.container {
+                args.push("-c".to_string());
+                args.push(container.to_string());
+            }
+            args.push("--".to_string());
+        }
+        args
+    }
+
+    /// a shell running the command in the container
+    pub fn command(&self, command: &str) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(self.exec_args(&self.name)).arg("sh").arg("-c").arg(command);
+        cmd
+    }
+
+    /// the remote shell given to `rsync -e`, rsync adds the name of the
+    /// container and the command after it
+    pub fn rsh(&self) -> Result<String> {
+        if self.program.contains(|c| "'\"$`\\".contains(c)) {
+            bail!("invalid {} program {:?}", self.runtime.program(), self.program);
+        }
+        Ok(format!(
+            r#"sh -c 'exec "{}" {} "$@"'"#,
+            self.program,
+            self.exec_args(r#""$0""#).join(" ")
+        ))
+    }
+
+    /// run a shell command in the container and return its stdout
+    pub fn exec(&self, command: &str) -> Result<String> {
+        debug!("run in {}: {}", self.name, command);
+        let output = self.command(command).stdin(Stdio::null()).output()?;
+        if !output.status.success() {
+            bail!(
+                "run {:?} in {} failed: {}",
+                command,
+                self.name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// the home directory of the user in the container
+    pub fn home(&self) -> Result<String> {
+        let home = self.exec("printf %s \"$HOME\"")?;
+        if home.is_empty() {
+            bail!("no home directory in {}", self.name);
+        }
+        Ok(home)
+    }
+
+    /// whether rsync is installed in the container, checked only if resolve
+    /// did not
+    pub fn has_rsync(&self) -> Result<bool> {
+        match self.rsync {
+            Some(rsync) => Ok(rsync),
+            None => Ok(self.exec("command -v rsync >/dev/null 2>&1 && echo yes || echo no")?.trim() == "yes"),
+        }
+    }
+
+    /// send the large files already in dest as deltas with `rn delta`, if
+    /// it can run in the container; returns the files sent
+    fn send_deltas(&self, host: &Host, project: &Project, files: Option<&[String]>, report: &mut SyncReport) -> Vec<String> {
+        let listing = format!(
+            "cd {} 2>/dev/null && find . -type f -size +{}k || true",
+            quote(&project.dest),
+            DELTA_MIN_SIZE / 1024
+        );
+        let candidates = match self.exec(&listing) {
+            Ok(listing) => select_deltas(project, files, listing.lines()),
+            Err(e) => {
+                debug!("list the files of {} failed: {}", self.name, e);
+                return Vec::new();
+            }
+        };
+        if candidates.is_empty() {
+            return Vec::new();
+        }
+        let rn = match delta::deploy(host) {
+            Ok(rn) => rn,
+            Err(e) => {
+                warn!("{}, the changed files are sent whole to {}", e, self.name);
+                return Vec::new();
+            }
+        };
+        let mut sent = Vec::new();
+        for relative in candidates {
+            let remote = format!("{}/{}", project.dest.trim_end_matches('/'), relative);
+            match delta::upload(host, &rn, &Path::new(&project.src).join(&relative), &remote) {
+                Ok(bytes) => {
+                    report.files.push(relative.clone());
+                    report.bytes += bytes;
+                    sent.push(relative);
+                }
+                Err(e) => warn!("send the delta of {} failed, send it with tar: {}", relative, e),
+            }
+        }
+        sent
+    }
+
+    /// send the files of src to dest with tar, for the images without rsync:
+    /// every file is sent, but the changes of the large ones already in dest,
+    /// and none is deleted. host is the one of the container
+    pub fn copy(&self, host: &Host, project: &Project, files: Option<&[String]>) -> Result<SyncReport> {
+        if !Path::new(&project.src).is_dir() {
+            bail!("project {}: src must be a directory to sync without rsync in {}", project.name, self.name);
+        }
+        let mut report = SyncReport::default();
+        let deltas = self.send_deltas(host, project, files, &mut report);
+        let mut tar = Command::new("tar");
+        tar.arg("-cvf").arg("-").arg("-C").arg(&project.src);
+        if let Some(ref exclude) = project.exclude {
+            for pattern in exclude.iter() {
+                tar.arg(format!("--exclude={}", pattern));
+            }
+        }
+        match files {
+            Some(files) => {
+                let rest: Vec<String> = files
+                    .iter()
+                    .filter(|file| !deltas.contains(file))
+                    .map(|file| format!("./{}", file))
+                    .collect();
+                if rest.is_empty() {
+                    return Ok(finish(report));
+                }
+                tar.args(rest)
+            }
+            None => {
+                // the files sent as deltas, by their exact names
+                tar.arg("--anchored").arg("--no-wildcards");
+                tar.args(deltas.iter().map(|file| format!("--exclude=./{}", file)));
+                tar.arg(".")
+            }
+        };
+        debug!("{:?}", tar);
+        let mut tar = tar.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        let extract = format!("mkdir -p {dest} && tar -xof - -C {dest}", dest = quote(&project.dest));
+        let mut extract = match self.command(&extract)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                let _ = tar.kill();
+                let _ = tar.wait();
+                return Err(e.into());
+            }
+        };
+        let listing = read_in_background(tar.stderr.take());
+        let remote_errors = read_in_background(extract.stderr.take());
+        let sent = match (tar.stdout.take(), extract.stdin.take()) {
+            // the input of the remote tar ends when its stdin is dropped
+            (Some(mut archive), Some(mut stdin)) => io::copy(&mut archive, &mut stdin),
+            _ => Err(io::Error::new(io::ErrorKind::BrokenPipe, "no pipe to tar")),
+        };
+        if sent.is_err() {
+            let _ = tar.kill();
+        }
+        let tar_status = tar.wait()?;
+        let extract_status = extract.wait()?;
+        let listing = listing.join().unwrap_or_default();
+        let remote_errors = remote_errors.join().unwrap_or_default();
+
+        for line in listing.lines() {
+            if line.starts_with("tar:") {
+                report.errors.push(line.to_string());
+            } else if !line.ends_with('/') {
+                report.files.push(line.trim_start_matches("./").to_string());
+            }
+        }
+        report.errors.extend(remote_errors.lines().filter(|line| line.trim().len() > 0).map(String::from));
+        match sent {
+            Ok(bytes) => report.bytes += bytes,
+            Err(e) => report.errors.push(format!("send to {} failed: {}", self.name, e)),
+        }
+        if !tar_status.success() {
+            report.errors.push(format!("tar exited with {}", tar_status));
+        }
+        if !extract_status.success() {
+            report.errors.push(format!("tar in {} exited with {}", self.name, extract_status));
+        }
+        Ok(finish(report))
+    }
+}
+
+/// a docker which runs the command here instead of in a container
+#[cfg(test)]
+pub fn fake_docker(dir: &Path) -> ::std::path::PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+    let path = dir.join("docker");
+    fs::write(&path, "#!/bin/sh\n# exec -i <container> <command>...\nshift 3\nexec \"$@\"\n").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+fn finish(mut report: SyncReport) -> SyncReport {
+    report.stats.files = report.files.len() as u64;
+    report.stats.files_transferred = report.files.len() as u64;
+    report.stats.bytes_sent = report.bytes;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use utils::rsync::sync;
+    use utils::sshconfig::Host;
+
+    #[test]
+    fn test_parse() {
+        let container = parse("docker://web/srv/app").unwrap();
+        assert_eq!(container.runtime, Runtime::Docker);
+        assert_eq!(container.program, "docker");
+        assert_eq!(container.name, "web");
+        assert_eq!(container.path, Some("/srv/app".to_string()));
+        assert_eq!(parse("podman://web").unwrap().path, None);
+
+        let pod = parse("k8s://dev/api-0/app/srv/app").unwrap();
+        assert_eq!(pod.namespace, Some("dev".to_string()));
+        assert_eq!(pod.name, "api-0");
+        assert_eq!(pod.container, Some("app".to_string()));
+        assert_eq!(pod.path, Some("/srv/app".to_string()));
+        let pod = parse("k8s://dev/api-0//srv/app").unwrap();
+        assert_eq!(pod.container, None);
+        assert_eq!(pod.path, Some("/srv/app".to_string()));
+
+        assert!(is_target("k8s://dev/api-0"));
+        assert!(!is_target("10.10.20.1"));
+        assert!(parse("k8s://dev").is_err());
+        assert!(parse("docker://").is_err());
+        assert!(parse("docker://we$b/srv").is_err());
+    }
+
+    #[test]
+    fn test_rsh() {
+        let pod = parse("k8s://dev/api-0/app").unwrap();
+        assert_eq!(
+            pod.rsh().unwrap(),
+            r#"sh -c 'exec "kubectl" exec -i -n dev "$0" -c app -- "$@"'"#
+        );
+        assert_eq!(
+            pod.command("ls").get_args().collect::<Vec<_>>(),
+            vec!["exec", "-i", "-n", "dev", "api-0", "-c", "app", "--", "sh", "-c", "ls"]
+        );
+        let mut container = parse("docker://web").unwrap();
+        assert_eq!(container.rsh().unwrap(), r#"sh -c 'exec "docker" exec -i "$0" "$@"'"#);
+        container.program = "it's".to_string();
+        assert!(container.rsh().is_err());
+    }
+
+    #[test]
+    fn test_copy() {
+        let dir = Path::new("/tmp").join(format!("rn_container_{}", std::process::id()));
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        for path in &["a.log", "keep.txt", "sub/c.txt"] {
+            fs::write(src.join(path), path).unwrap();
+        }
+        let mut container = parse(&format!("docker://web{}/dest", dir.display())).unwrap();
+        container.program = fake_docker(&dir).to_string_lossy().into_owned();
+        assert!(container.home().is_ok());
+
+        let project = Project {
+            name: "container".to_string(),
+            src: src.to_string_lossy().into_owned(),
+            dest: container.path.clone().unwrap(),
+            exclude: Some(vec!["*.log".to_string()]),
+            ..Default::default()
+        };
+        let host = Host::container(&format!("docker://web{}/dest", dir.display()), container.clone());
+        let mut report = container.copy(&host, &project, None).unwrap();
+        report.files.sort();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.files, vec!["keep.txt", "sub/c.txt"]);
+        let dest = dir.join("dest");
+        assert_eq!(fs::read_to_string(dest.join("sub/c.txt")).unwrap(), "sub/c.txt");
+        assert!(!dest.join("a.log").exists());
+
+        fs::write(src.join("keep.txt"), "changed").unwrap();
+        let report = container.copy(&host, &project, Some(&["keep.txt".to_string()])).unwrap();
+        assert_eq!(report.files, vec!["keep.txt"]);
+        assert_eq!(fs::read_to_string(dest.join("keep.txt")).unwrap(), "changed");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_select_deltas() {
+        let dir = Path::new("/tmp").join(format!("rn_container_deltas_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for path in &["a.bin", "b.log", "sub/c.bin"] {
+            fs::write(dir.join(path), path).unwrap();
+        }
+        let project: Project = ::toml::from_str(&format!(
+            "name = \"web\"\nsrc = \"{}\"\ndest = \"/srv/web\"\nexclude = [\"*.log\"]\n",
+            dir.display()
+        )).unwrap();
+        // gone.bin is only in dest
+        let listing = "./a.bin\n./b.log\n./sub/c.bin\n./gone.bin\n";
+        assert_eq!(select_deltas(&project, None, listing.lines()), vec!["a.bin", "sub/c.bin"]);
+        let files = vec!["sub/c.bin".to_string(), "new.bin".to_string()];
+        assert_eq!(select_deltas(&project, Some(&files), listing.lines()), vec!["sub/c.bin"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sync() {
+        let dir = Path::new("/tmp").join(format!("rn_container_sync_{}", std::process::id()));
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        for path in &["a.log", "sub/c.txt"] {
+            fs::write(src.join(path), path).unwrap();
+        }
+        let target = format!("docker://web{}/dest", dir.display());
+        let mut container = parse(&target).unwrap();
+        container.program = fake_docker(&dir).to_string_lossy().into_owned();
+        let project = Project {
+            name: "container".to_string(),
+            src: src.to_string_lossy().into_owned(),
+            dest: format!("{}/", container.path.clone().unwrap()),
+            exclude: Some(vec!["*.log".to_string()]),
+            ..Default::default()
+        };
+        // through the rsh of rsync if it is installed, with tar otherwise
+        let report = sync(&Host::container(&target, container), &project, false).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.host, target);
+        assert_eq!(report.files, vec!["sub/c.txt"]);
+        assert_eq!(fs::read_to_string(dir.join("dest/sub/c.txt")).unwrap(), "sub/c.txt");
+        assert!(!dir.join("dest/a.log").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,643 @@
+use errors::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::{env, fs};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Stdio;
+use super::{agent, ssh};
+use super::sshconfig::Host;
+use super::wire::{put_bytes, put_u32, put_u64, read_frame, write_frame, Fields};
+
+/// the smallest and largest block sizes, between them the square root of the file length
+const MIN_BLOCK_SIZE: usize = 700;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+/// the bytes of the sha256 kept for each block
+const STRONG_LENGTH: usize = 16;
+/// the most literal data in a frame of the delta, the frames stay far
+/// below the limit of the wire whatever the size of the file
+const FRAME_DATA: usize = 1024 * 1024;
+/// the frames of a delta: its block size, operations, then the checksum
+const FRAME_START: u8 = 0;
+const FRAME_OPS: u8 = 1;
+const FRAME_END: u8 = 2;
+
+/// the block size for a file, like rsync picks it
+pub fn block_size_for(length: u64) -> usize {
+    let size = (length as f64).sqrt() as usize / 8 * 8;
+    size.max(MIN_BLOCK_SIZE).min(MAX_BLOCK_SIZE)
+}
+
+/// the rsync rolling checksum of a window, it can be moved by one byte
+/// without reading the window again
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let length = window.len() as u32;
+        let mut rolling = Rolling { a: 0, b: 0, length };
+        for (i, &byte) in window.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(byte as u32);
+            rolling.b = rolling.b.wrapping_add((length - i as u32).wrapping_mul(byte as u32));
+        }
+        rolling
+    }
+
+    /// drop the first byte of the window and add one at its end
+    fn roll(&mut self, out: u8, ins: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(ins as u32);
+        self.b = self.b.wrapping_sub(self.length.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; STRONG_LENGTH] {
+    let mut strong = [0u8; STRONG_LENGTH];
+    strong.copy_from_slice(&Sha256::digest(block)[..STRONG_LENGTH]);
+    strong
+}
+
+/// the checksums of the blocks of the old file, all of block_size but the last one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub block_size: usize,
+    /// the length of the old file
+    pub length: u64,
+    pub blocks: Vec<(u32, [u8; STRONG_LENGTH])>,
+}
+
+/// how to build the new file from the old one
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// count blocks of the old file from index
+    Copy { index: u32, count: u32 },
+    /// bytes which are not in the old file
+    Data(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    pub block_size: usize,
+    pub ops: Vec<Op>,
+    /// the sha256 of the new file, checked after patching
+    pub checksum: Vec<u8>,
+}
+
+pub fn signature(old: &[u8], block_size: usize) -> Signature {
+    Signature {
+        block_size,
+        length: old.len() as u64,
+        blocks: old
+            .chunks(block_size)
+            .map(|block| (Rolling::new(block).digest(), strong(block)))
+            .collect(),
+    }
+}
+
+fn push_data(ops: &mut Vec<Op>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    if let Some(&mut Op::Data(ref mut last)) = ops.last_mut() {
+        last.extend_from_slice(data);
+        return;
+    }
+    ops.push(Op::Data(data.to_vec()));
+}
+
+fn push_copy(ops: &mut Vec<Op>, index: u32) {
+    if let Some(&mut Op::Copy { index: start, ref mut count }) = ops.last_mut() {
+        if start + *count == index {
+            *count += 1;
+            return;
+        }
+    }
+    ops.push(Op::Copy { index, count: 1 });
+}
+
+/// the operations building new from the blocks of the old file and literal data
+pub fn delta(signature: &Signature, new: &[u8]) -> Delta {
+    let size = signature.block_size;
+    if size == 0 {
+        // no block to copy from
+        let mut ops = Vec::new();
+        push_data(&mut ops, new);
+        return Delta {
+            block_size: size,
+            ops,
+            checksum: Sha256::digest(new).to_vec(),
+        };
+    }
+    let mut index: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (i, block) in signature.blocks.iter().enumerate() {
+        // the last block may be short, it is only matched at the end
+        if (i as u64 + 1) * size as u64 <= signature.length {
+            index.entry(block.0).or_insert_with(Vec::new).push(i as u32);
+        }
+    }
+    let mut ops = Vec::new();
+    // the start of the data not matched yet
+    let mut literal = 0;
+    let mut position = 0;
+    let mut rolling: Option<Rolling> = None;
+    while position + size <= new.len() {
+        let window = &new[position..position + size];
+        let mut current = rolling.unwrap_or_else(|| Rolling::new(window));
+        if let Some(candidates) = index.get(&current.digest()) {
+            let checksum = strong(window);
+            if let Some(&block) = candidates.iter().find(|&&i| signature.blocks[i as usize].1 == checksum) {
+                push_data(&mut ops, &new[literal..position]);
+                push_copy(&mut ops, block);
+                position += size;
+                literal = position;
+                rolling = None;
+                continue;
+            }
+        }
+        if position + size < new.len() {
+            current.roll(new[position], new[position + size]);
+        }
+        rolling = Some(current);
+        position += 1;
+    }
+    // the new file may end with the short last block of the old one
+    let mut end = new.len();
+    if let Some(last) = signature.blocks.last() {
+        let length = (signature.length % size as u64) as usize;
+        if length > 0 && new.len() - literal >= length {
+            let tail = &new[new.len() - length..];
+            if Rolling::new(tail).digest() == last.0 && strong(tail) == last.1 {
+                end = new.len() - length;
+                push_data(&mut ops, &new[literal..end]);
+                push_copy(&mut ops, signature.blocks.len() as u32 - 1);
+            }
+        }
+    }
+    if end == new.len() {
+        push_data(&mut ops, &new[literal..]);
+    }
+    Delta {
+        block_size: size,
+        ops,
+        checksum: Sha256::digest(new).to_vec(),
+    }
+}
+
+/// writes the new file one operation at a time
+struct Patcher<'a> {
+    old: &'a [u8],
+    block_size: usize,
+    hasher: Sha256,
+}
+
+impl<'a> Patcher<'a> {
+    fn new(old: &'a [u8], block_size: usize) -> Self {
+        Patcher {
+            old,
+            block_size,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn apply<W: Write>(&mut self, op: &Op, output: &mut W) -> Result<()> {
+        let data = match *op {
+            Op::Copy { index, count } => {
+                // the values come from the network, they may overflow
+                let range = (index as usize).checked_mul(self.block_size).and_then(|start| {
+                    (count as usize)
+                        .checked_mul(self.block_size)
+                        .and_then(|length| start.checked_add(length))
+                        .map(|end| (start, end.min(self.old.len())))
+                });
+                match range {
+                    Some((start, end)) if start < end => &self.old[start..end],
+                    _ => bail!("blocks {} to {} are not in the old file", index, index as u64 + count as u64),
+                }
+            }
+            Op::Data(ref data) => data.as_slice(),
+        };
+        self.hasher.update(data);
+        output.write_all(data)?;
+        Ok(())
+    }
+
+    fn finish(self, checksum: &[u8]) -> Result<()> {
+        if self.hasher.finalize().as_slice() != checksum {
+            bail!("the patched file does not match the checksum of the new one");
+        }
+        Ok(())
+    }
+}
+
+/// build the new file from the old one and the delta
+pub fn patch<W: Write>(old: &[u8], delta: &Delta, output: &mut W) -> Result<()> {
+    let mut patcher = Patcher::new(old, delta.block_size);
+    for op in delta.ops.iter() {
+        patcher.apply(op, output)?;
+    }
+    patcher.finish(&delta.checksum)
+}
+
+impl Signature {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_u32(&mut buf, self.block_size as u32);
+        put_u64(&mut buf, self.length);
+        put_u32(&mut buf, self.blocks.len() as u32);
+        for &(weak, ref strong) in self.blocks.iter() {
+            put_u32(&mut buf, weak);
+            buf.extend_from_slice(strong);
+        }
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Signature> {
+        let mut fields = Fields::new(payload);
+        let block_size = fields.u32()? as usize;
+        let length = fields.u64()?;
+        let count = fields.u32()? as usize;
+        let expected = match block_size as u64 {
+            0 => None,
+            size => length.checked_add(size).map(|end| (end - 1) / size),
+        };
+        // the blocks must be in the payload before room is made for them
+        if expected != Some(count as u64)
+            || count.checked_mul(4 + STRONG_LENGTH).map_or(true, |size| size > fields.len())
+        {
+            bail!("invalid signature of {} blocks of {} bytes for {} bytes", count, block_size, length);
+        }
+        let mut blocks = Vec::with_capacity(count);
+        for _ in 0..count {
+            let weak = fields.u32()?;
+            let mut strong = [0u8; STRONG_LENGTH];
+            strong.copy_from_slice(fields.take(STRONG_LENGTH)?);
+            blocks.push((weak, strong));
+        }
+        Ok(Signature { block_size, length, blocks })
+    }
+}
+
+impl Delta {
+    /// the bytes sent as data, the others are copied from the old file
+    pub fn data_length(&self) -> u64 {
+        self.ops
+            .iter()
+            .map(|op| match *op {
+                Op::Data(ref data) => data.len() as u64,
+                Op::Copy { .. } => 0,
+            })
+            .sum()
+    }
+}
+
+fn put_op(buf: &mut Vec<u8>, op: &Op) {
+    match *op {
+        Op::Copy { index, count } => {
+            buf.push(0);
+            put_u32(buf, index);
+            put_u32(buf, count);
+        }
+        Op::Data(ref data) => {
+            buf.push(1);
+            put_bytes(buf, data);
+        }
+    }
+}
+
+fn read_op(fields: &mut Fields) -> Result<Op> {
+    let op = match fields.u8()? {
+        0 => Op::Copy {
+            index: fields.u32()?,
+            count: fields.u32()?,
+        },
+        1 => Op::Data(fields.bytes()?),
+        op => bail!("unknown delta operation {}", op),
+    };
+    Ok(op)
+}
+
+/// send the delta in frames holding at most FRAME_DATA bytes of data each
+pub fn write_delta<W: Write>(delta: &Delta, output: &mut W) -> io::Result<()> {
+    let mut buf = vec![FRAME_START];
+    put_u32(&mut buf, delta.block_size as u32);
+    write_frame(output, &buf)?;
+    let mut buf = vec![FRAME_OPS];
+    for op in delta.ops.iter() {
+        let chunks: Vec<Op> = match *op {
+            Op::Data(ref data) => data.chunks(FRAME_DATA).map(|chunk| Op::Data(chunk.to_vec())).collect(),
+            Op::Copy { .. } => vec![op.clone()],
+        };
+        for chunk in chunks.iter() {
+            let length = match *chunk {
+                Op::Data(ref data) => data.len(),
+                Op::Copy { .. } => 0,
+            };
+            if buf.len() > 1 && buf.len() + length > FRAME_DATA {
+                write_frame(output, &buf)?;
+                buf.truncate(1);
+            }
+            put_op(&mut buf, chunk);
+        }
+    }
+    if buf.len() > 1 {
+        write_frame(output, &buf)?;
+    }
+    let mut buf = vec![FRAME_END];
+    buf.extend_from_slice(&delta.checksum);
+    write_frame(output, &buf)
+}
+
+/// read the frames of a delta up to its end without applying them, to
+/// keep reading the input after a failure
+pub fn skip_delta<R: Read>(input: &mut R) -> Result<()> {
+    loop {
+        match read_frame(input)? {
+            Some(ref frame) if frame.first() == Some(&FRAME_END) => return Ok(()),
+            Some(_) => {}
+            None => bail!("the delta ended early"),
+        }
+    }
+}
+
+/// patch old with the frames of `write_delta` into output as they are
+/// read; all of them are read even if patching fails
+pub fn read_delta<R: Read, W: Write>(old: &[u8], input: &mut R, output: &mut W) -> Result<()> {
+    let block_size = match read_frame(input)? {
+        Some(ref frame) if frame.first() == Some(&FRAME_START) => Fields::new(&frame[1..]).u32()? as usize,
+        Some(_) => bail!("the delta does not start with its block size"),
+        None => bail!("no delta received"),
+    };
+    let mut patcher = Patcher::new(old, block_size);
+    loop {
+        let frame = match read_frame(input)? {
+            Some(frame) => frame,
+            None => bail!("the delta ended early"),
+        };
+        match frame.first() {
+            Some(&FRAME_OPS) => {
+                let mut fields = Fields::new(&frame[1..]);
+                let result = (|| -> Result<()> {
+                    while !fields.is_empty() {
+                        patcher.apply(&read_op(&mut fields)?, output)?;
+                    }
+                    Ok(())
+                })();
+                if let Err(e) = result {
+                    skip_delta(input)?;
+                    return Err(e);
+                }
+            }
+            Some(&FRAME_END) => return patcher.finish(&frame[1..]),
+            _ => {
+                skip_delta(input)?;
+                bail!("unknown delta frame");
+            }
+        }
+    }
+}
+
+/// the remote side: send the signature of the file, read the delta, then
+/// replace the file with the patched one and reply with an empty frame, or
+/// with the error
+pub fn apply<R: Read, W: Write>(path: &Path, mut input: R, mut output: W) -> Result<()> {
+    let old = match fs::read(path) {
+        Ok(old) => old,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    write_frame(&mut output, &signature(&old, block_size_for(old.len() as u64)).encode())?;
+    let mut read = false;
+    let result = (|| -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let temp = dir.join(format!(".{}.rn-tmp", name));
+        let mut file = fs::File::create(&temp)?;
+        read = true;
+        if let Err(e) = read_delta(&old, &mut input, &mut file) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(fs::Permissions::from_mode(metadata.permissions().mode()))?;
+        }
+        fs::rename(&temp, path)?;
+        Ok(())
+    })();
+    if !read {
+        skip_delta(&mut input)?;
+    }
+    let reply = match result {
+        Ok(()) => Vec::new(),
+        Err(ref e) => e.to_string().into_bytes(),
+    };
+    write_frame(&mut output, &reply)?;
+    result
+}
+
+/// the local side of `apply`: send the delta of new against the signature
+/// read from input, returns the bytes of data sent
+pub fn send<R: Read, W: Write>(new: &[u8], mut input: R, mut output: W) -> Result<u64> {
+    let signature = match read_frame(&mut input)? {
+        Some(payload) => Signature::decode(&payload)?,
+        None => bail!("no signature received"),
+    };
+    let delta = delta(&signature, new);
+    write_delta(&delta, &mut output)?;
+    match read_frame(&mut input)? {
+        Some(ref reply) if reply.is_empty() => Ok(delta.data_length()),
+        Some(reply) => bail!("apply the delta failed: {}", String::from_utf8_lossy(&reply)),
+        None => bail!("apply the delta failed: no reply"),
+    }
+}
+
+/// the rn to run on the host for `upload`, copied there if needed
+pub fn deploy(host: &Host) -> Result<String> {
+    if host.is_local() {
+        return Ok(env::current_exe()?.to_string_lossy().into_owned());
+    }
+    agent::deploy(host)
+}
+
+/// update a file on the host with the rn of `deploy`, sending only the
+/// changed blocks. Returns the bytes of data sent
+pub fn upload(host: &Host, rn: &str, local: &Path, remote: &str) -> Result<u64> {
+    let remote_command = format!("{} delta {}", ssh::quote(rn), ssh::quote(remote));
+    let mut child = ssh::command(host, &remote_command)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let result = match (child.stdout.take(), child.stdin.take()) {
+        (Some(stdout), Some(stdin)) => send(&fs::read(local)?, stdout, stdin),
+        _ => Err("no pipe to rn delta".into()),
+    };
+    let status = child.wait()?;
+    let sent = result?;
+    if !status.success() {
+        bail!("rn delta on {} exited with {}", host.hostname, status);
+    }
+    debug!("{:?} -> {}:{}, {} bytes of data sent", local, host.hostname, remote, sent);
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::TestResult;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn roundtrip(old: &[u8], new: &[u8], block_size: usize) -> Vec<u8> {
+        let signature = Signature::decode(&signature(old, block_size).encode()).unwrap();
+        let mut frames = Vec::new();
+        write_delta(&delta(&signature, new), &mut frames).unwrap();
+        let mut patched = Vec::new();
+        read_delta(old, &mut frames.as_slice(), &mut patched).unwrap();
+        patched
+    }
+
+    quickcheck! {
+        fn prop_patch(old: Vec<u8>, new: Vec<u8>, block_size: u8) -> TestResult {
+            if block_size == 0 {
+                return TestResult::discard();
+            }
+            TestResult::from_bool(roundtrip(&old, &new, block_size as usize) == new)
+        }
+
+        fn prop_patch_edit(old: Vec<u8>, at: usize, removed: u8, inserted: Vec<u8>, block_size: u8) -> TestResult {
+            if block_size == 0 {
+                return TestResult::discard();
+            }
+            // new is old with a part replaced, most blocks are copied
+            let at = if old.is_empty() { 0 } else { at % old.len() };
+            let end = (at + removed as usize).min(old.len());
+            let mut new = old[..at].to_vec();
+            new.extend_from_slice(&inserted);
+            new.extend_from_slice(&old[end..]);
+            TestResult::from_bool(roundtrip(&old, &new, block_size as usize) == new)
+        }
+    }
+
+    #[test]
+    fn test_delta() {
+        let old: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        new.splice(5000..5010, b"inserted".iter().cloned());
+        let delta = delta(&signature(&old, 1000), &new);
+        // the block around the change and the data inserted
+        assert!(delta.data_length() < 1100, "{}", delta.data_length());
+        assert_eq!(delta.ops[0], Op::Copy { index: 0, count: 5 });
+
+        let same = super::delta(&signature(&old, 1000), &old);
+        assert_eq!(same.ops, vec![Op::Copy { index: 0, count: 10 }]);
+        // a wrong checksum is refused
+        let mut wrong = same.clone();
+        wrong.checksum[0] ^= 1;
+        assert!(patch(&old, &wrong, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_malformed() {
+        // a signature without a block size is not divided by
+        let zero = Signature {
+            block_size: 0,
+            length: 10,
+            blocks: vec![(0, [0u8; STRONG_LENGTH])],
+        };
+        let new = b"new content".to_vec();
+        let mut patched = Vec::new();
+        patch(&[], &delta(&zero, &new), &mut patched).unwrap();
+        assert_eq!(patched, new);
+
+        // a length overflowing with the block size, and more blocks than the payload holds
+        let mut payload = Vec::new();
+        put_u32(&mut payload, 1024);
+        put_u64(&mut payload, u64::MAX);
+        put_u32(&mut payload, 0);
+        assert!(Signature::decode(&payload).is_err());
+        let mut payload = Vec::new();
+        put_u32(&mut payload, 1);
+        put_u64(&mut payload, u32::MAX as u64);
+        put_u32(&mut payload, u32::MAX);
+        assert!(Signature::decode(&payload).is_err());
+
+        // copies overflowing the size of the old file
+        let old = vec![1u8; 100];
+        let mut patcher = Patcher::new(&old, 1 << 20);
+        let copy = Op::Copy {
+            index: u32::MAX,
+            count: u32::MAX,
+        };
+        assert!(patcher.apply(&copy, &mut Vec::new()).is_err());
+        let copy = Op::Copy {
+            index: 0,
+            count: u32::MAX,
+        };
+        let mut patcher = Patcher::new(&old, usize::MAX);
+        assert!(patcher.apply(&copy, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_frames() {
+        // nothing in common, all the file is sent as data
+        let new: Vec<u8> = (0..5 * FRAME_DATA as u32 / 2).map(|i| (i % 251) as u8).collect();
+        let delta = delta(&signature(&[], 1000), &new);
+        let mut frames = Vec::new();
+        write_delta(&delta, &mut frames).unwrap();
+        let mut input = frames.as_slice();
+        let mut count = 0;
+        while let Some(frame) = read_frame(&mut input).unwrap() {
+            assert!(frame.len() <= FRAME_DATA + 16, "{}", frame.len());
+            count += 1;
+        }
+        // start, three of data and end
+        assert_eq!(count, 5);
+
+        let mut patched = Vec::new();
+        read_delta(&[], &mut frames.as_slice(), &mut patched).unwrap();
+        assert_eq!(patched, new);
+
+        // a failed patch still reads all the frames
+        let mut wrong = delta.clone();
+        wrong.ops.insert(0, Op::Copy { index: 3, count: 1 });
+        let mut frames = Vec::new();
+        write_delta(&wrong, &mut frames).unwrap();
+        frames.extend_from_slice(b"next");
+        let mut input = frames.as_slice();
+        assert!(read_delta(&[], &mut input, &mut Vec::new()).is_err());
+        assert_eq!(input, b"next");
+    }
+
+    #[test]
+    fn test_apply() {
+        let dir = env::temp_dir().join(format!("rn_delta_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.bin");
+        let old: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(&path, &old).unwrap();
+        let mut new = old.clone();
+        new.extend_from_slice(b"appended");
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let remote = path.clone();
+        let apply_thread = thread::spawn(move || apply(&remote, server.try_clone().unwrap(), server));
+        let sent = send(&new, client.try_clone().unwrap(), client).unwrap();
+        apply_thread.join().unwrap().unwrap();
+        assert!(sent < 2 * block_size_for(old.len() as u64) as u64, "{}", sent);
+        assert_eq!(fs::read(&path).unwrap(), new);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use errors::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// run git in a directory, None if it fails, e.g. the directory is not in a git repo
+fn output<P: AsRef<Path>>(dir: P, args: &[&str]) -> Option<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir.as_ref())
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(output.stdout)
+}
+
+fn git<P: AsRef<Path>>(dir: P, args: &[&str]) -> Option<String> {
+    let stdout = output(dir, args)?;
+    Some(String::from_utf8_lossy(&stdout).trim().to_string())
+}
+
+/// the paths of a `-z` output
+fn split_paths(stdout: &[u8]) -> Vec<String> {
+    stdout
+        .split(|&b| b == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| String::from_utf8_lossy(path).into_owned())
+        .collect()
+}
+
+/// the commit checked out in the repo containing `dir`, with "-dirty"
+/// appended if there are uncommitted changes
+pub fn revision<P: AsRef<Path>>(dir: P) -> Option<String> {
+    let dir = dir.as_ref();
+    // a file as src: ask git in its directory
+    let dir = if dir.is_file() { dir.parent()? } else { dir };
+    let commit = git(dir, &["rev-parse", "HEAD"])?;
+    match git(dir, &["status", "--porcelain", "--untracked-files=no"]) {
+        Some(ref changes) if changes.len() > 0 => Some(format!("{}-dirty", commit)),
+        _ => Some(commit),
+    }
+}
+
+/// the branch checked out in the repo containing `dir`, None if HEAD is detached
+pub fn branch<P: AsRef<Path>>(dir: P) -> Option<String> {
+    let dir = dir.as_ref();
+    let dir = if dir.is_file() { dir.parent()? } else { dir };
+    match git(dir, &["rev-parse", "--abbrev-ref", "HEAD"]) {
+        Some(ref branch) if branch == "HEAD" => None,
+        branch => branch,
+    }
+}
+
+/// the root of the repo containing `dir`
+pub fn toplevel<P: AsRef<Path>>(dir: P) -> Option<PathBuf> {
+    git(dir, &["rev-parse", "--show-toplevel"]).map(PathBuf::from)
+}
+
+/// the name of the repo containing `dir`: the last part of the url of
+/// origin without ".git", or the name of the root directory
+pub fn repo_name<P: AsRef<Path>>(dir: P) -> Option<String> {
+    let dir = dir.as_ref();
+    if let Some(url) = git(dir, &["config", "--get", "remote.origin.url"]) {
+        let name = url.trim_end_matches('/').trim_end_matches(".git");
+        // git@github.com:user/repo.git or https://github.com/user/repo
+        if let Some(name) = name.rsplit(|c| c == '/' || c == ':').next() {
+            if !name.is_empty() {
+                return Some(name.to_string());
+            }
+        }
+    }
+    toplevel(dir)?.file_name().map(|name| name.to_string_lossy().into_owned())
+}
+
+/// the files tracked by git in dir, relative to dir
+pub fn tracked_files<P: AsRef<Path>>(dir: P) -> Result<Vec<String>> {
+    let dir = dir.as_ref();
+    match output(dir, &["ls-files", "-z"]) {
+        Some(stdout) => Ok(split_paths(&stdout)),
+        None => bail!("list the files tracked by git in {:?} failed, is it in a git repo?", dir),
+    }
+}
+
+/// the files in dir which were changed since `since`, committed or not,
+/// and the untracked files which are not ignored; relative to dir
+pub fn changed_files<P: AsRef<Path>>(dir: P, since: &str) -> Result<Vec<String>> {
+    let dir = dir.as_ref();
+    let changed = match output(dir, &["diff", "--name-only", "--relative", "-z", since, "--"]) {
+        Some(stdout) => split_paths(&stdout),
+        None => bail!("list the files changed in {:?} since {:?} failed, is it a commit?", dir, since),
+    };
+    let untracked = output(dir, &["ls-files", "--others", "--exclude-standard", "-z"]).unwrap_or_default();
+    let mut files = changed;
+    for path in split_paths(&untracked) {
+        if !files.contains(&path) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_revision() {
+        assert_eq!(revision("/"), None);
+        assert_eq!(branch("/"), None);
+        assert_eq!(repo_name("/"), None);
+    }
+
+    #[test]
+    fn test_changed_files() {
+        let dir = Path::new("/tmp").join(format!("rn_git_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let run = |args: &[&str]| assert!(output(&dir, args).is_some(), "git {:?} failed", args);
+        run(&["init", "-q"]);
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("sub/b c"), "b").unwrap();
+        run(&["add", "."]);
+        run(&["-c", "user.name=rn", "-c", "user.email=rn@localhost", "commit", "-q", "-m", "init"]);
+        fs::write(dir.join("a"), "changed").unwrap();
+        fs::write(dir.join("new"), "new").unwrap();
+
+        assert_eq!(tracked_files(&dir).unwrap(), vec!["a".to_string(), "sub/b c".to_string()]);
+        assert_eq!(tracked_files(dir.join("sub")).unwrap(), vec!["b c".to_string()]);
+        assert_eq!(changed_files(&dir, "HEAD").unwrap(), vec!["a".to_string(), "new".to_string()]);
+        assert!(changed_files(&dir, "not-a-ref").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
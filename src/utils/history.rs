@@ -0,0 +1,251 @@
+use errors::*;
+use chrono::{Local, TimeZone};
+use serde_json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::git;
+use super::rsync::{SyncReport, SyncStats};
+use super::sshconfig::Host;
+use super::toml_parser::Project;
+use super::util::data_dir;
+
+/// a finished sync as kept in the history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    pub project: String,
+    pub host: String,
+    pub dest: String,
+    /// the git commit of src, if src is in a git repo
+    pub commit: Option<String>,
+    pub files: usize,
+    pub deleted: usize,
+    /// in seconds
+    pub duration: f64,
+    pub errors: usize,
+    /// "ok" or "failed"
+    pub result: String,
+    pub stats: SyncStats,
+}
+
+impl Record {
+    pub fn new(report: &SyncReport, project: &Project) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Record {
+            timestamp,
+            project: report.project.clone(),
+            host: report.host.clone(),
+            dest: project.dest.clone(),
+            commit: git::revision(&project.src),
+            files: report.files.len(),
+            deleted: report.deleted.len(),
+            duration: report.duration,
+            errors: report.errors.len(),
+            result: if report.errors.is_empty() { "ok" } else { "failed" }.to_string(),
+            stats: report.stats.clone(),
+        }
+    }
+
+    /// a sync which failed before it could report anything, e.g. rsync
+    /// could not be started or was cancelled
+    pub fn failed(project: &Project, host: &Host) -> Self {
+        let report = SyncReport {
+            project: project.name.clone(),
+            host: host.hostname.clone(),
+            errors: vec!["failed".to_string()],
+            ..Default::default()
+        };
+        Record::new(&report, project)
+    }
+}
+
+fn history_dir() -> Result<PathBuf> {
+    let dir = data_dir()?.join("history");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// each project has its own file of json lines
+fn history_file(dir: &Path, project: &str) -> PathBuf {
+    dir.join(format!("{}.jsonl", project.replace("/", "_")))
+}
+
+fn append_to(dir: &Path, record: &Record) -> Result<()> {
+    let path = history_file(dir, &record.project);
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+/// append the record to the history of its project
+pub fn append(record: &Record) -> Result<()> {
+    append_to(&history_dir()?, record)
+}
+
+fn outcome(project: &Project, host: &Host, result: &Result<SyncReport>) -> Record {
+    match *result {
+        Ok(ref report) => Record::new(report, project),
+        Err(ref e) => match *e.kind() {
+            ErrorKind::SyncFailed(ref report) => Record::new(report, project),
+            _ => Record::failed(project, host),
+        },
+    }
+}
+
+/// append the result of a sync to the history, the failed ones too; a
+/// history which can not be written is only logged
+pub fn record(project: &Project, host: &Host, result: &Result<SyncReport>) {
+    if let Err(e) = append(&outcome(project, host, result)) {
+        warn!("write sync history failed: {}", e);
+    }
+}
+
+fn read_file(path: &Path, records: &mut Vec<Record>) -> Result<()> {
+    let file = BufReader::new(File::open(path)?);
+    for (n, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("skip line {} of {:?}: {}", n + 1, path, e),
+        }
+    }
+    Ok(())
+}
+
+fn query_in(dir: &Path, project: Option<&str>, host: Option<&str>) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    match project {
+        Some(project) => {
+            let path = history_file(dir, project);
+            if path.exists() {
+                read_file(&path, &mut records)?;
+            }
+        }
+        None => {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().map_or(false, |ext| ext == "jsonl") {
+                    read_file(&path, &mut records)?;
+                }
+            }
+        }
+    }
+    if let Some(host) = host {
+        records.retain(|record| record.host == host);
+    }
+    records.sort_by_key(|record| record.timestamp);
+    Ok(records)
+}
+
+/// the records of a project and/or a host, oldest first
+pub fn query(project: Option<&str>, host: Option<&str>) -> Result<Vec<Record>> {
+    query_in(&history_dir()?, project, host)
+}
+
+pub fn format_time(timestamp: u64) -> String {
+    match Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// print the records as a table
+pub fn print(records: &[Record]) {
+    println!(
+        "{:<19}  {:<16} {:<16} {:<24} {:<12} {:>6}  {}",
+        "TIME", "PROJECT", "HOST", "DEST", "COMMIT", "FILES", "RESULT"
+    );
+    for record in records {
+        let commit = match record.commit {
+            Some(ref commit) if commit.ends_with("-dirty") => format!("{}-dirty", &commit[..7.min(commit.len())]),
+            Some(ref commit) => commit[..7.min(commit.len())].to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<19}  {:<16} {:<16} {:<24} {:<12} {:>6}  {}",
+            format_time(record.timestamp),
+            record.project,
+            record.host,
+            record.dest,
+            commit,
+            record.files,
+            record.result
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn record(timestamp: u64, project: &str, host: &str) -> Record {
+        Record {
+            timestamp,
+            project: project.to_string(),
+            host: host.to_string(),
+            dest: "/home/ubuntu/a".to_string(),
+            commit: None,
+            files: 1,
+            deleted: 0,
+            duration: 0.5,
+            errors: 0,
+            result: "ok".to_string(),
+            stats: SyncStats::default(),
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let dir = Path::new("/tmp").join(format!("rn_history_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        append_to(&dir, &record(3, "a", "10.10.20.20")).unwrap();
+        append_to(&dir, &record(1, "a", "10.10.20.21")).unwrap();
+        append_to(&dir, &record(2, "b", "10.10.20.20")).unwrap();
+
+        let records = query_in(&dir, None, Some("10.10.20.20")).unwrap();
+        assert_eq!(records, vec![record(2, "b", "10.10.20.20"), record(3, "a", "10.10.20.20")]);
+
+        let records = query_in(&dir, Some("a"), None).unwrap();
+        assert_eq!(records, vec![record(1, "a", "10.10.20.21"), record(3, "a", "10.10.20.20")]);
+
+        assert!(query_in(&dir, Some("c"), None).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_outcome() {
+        let project: Project = ::toml::from_str("name = \"a\"\nsrc = \"/tmp\"\ndest = \"/home/ubuntu/a\"\n").unwrap();
+        let host = Host::local();
+        let report = SyncReport {
+            project: "a".to_string(),
+            host: host.hostname.clone(),
+            files: vec!["x.txt".to_string()],
+            ..Default::default()
+        };
+        let record = outcome(&project, &host, &Ok(report.clone()));
+        assert_eq!((record.files, record.errors, record.result.as_str()), (1, 0, "ok"));
+
+        let failed = SyncReport {
+            errors: vec!["rsync exited with 23".to_string()],
+            ..report
+        };
+        let record = outcome(&project, &host, &Err(ErrorKind::SyncFailed(Box::new(failed)).into()));
+        assert_eq!((record.files, record.errors, record.result.as_str()), (1, 1, "failed"));
+
+        let interrupted = ::std::io::Error::new(::std::io::ErrorKind::Interrupted, "transfer cancelled");
+        let record = outcome(&project, &host, &Err(interrupted.into()));
+        assert_eq!(record.project, "a");
+        assert_eq!(record.host, host.hostname);
+        assert_eq!((record.files, record.errors, record.result.as_str()), (0, 1, "failed"));
+    }
+}
@@ -0,0 +1,348 @@
+use errors::*;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use super::{git, release, rsync, ssh};
+use super::rsync::PARTIAL_DIR;
+use super::sshconfig::Host;
+use super::toml_parser::{Project, SourceMode};
+use super::transfer::SymlinkPolicy;
+use super::util::{create_re, is_exclude};
+
+/// the manifest is kept in `dest` under this name
+pub const MANIFEST_FILE: &str = ".rn-manifest";
+
+/// relative path -> hex encoded sha256
+pub type Hashes = BTreeMap<String, String>;
+
+/// what is written to `dest/.rn-manifest`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    /// the git revision of src
+    pub revision: Option<String>,
+    pub hashes: Hashes,
+}
+
+impl Manifest {
+    /// a "# revision: <rev>" header and one `sha256sum` style line per file
+    pub fn render(&self) -> String {
+        let mut text = String::from("# rn manifest\n");
+        if let Some(ref revision) = self.revision {
+            text.push_str(&format!("# revision: {}\n", revision));
+        }
+        for (path, hash) in self.hashes.iter() {
+            text.push_str(&format!("{}  {}\n", hash, path));
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut manifest = Manifest::default();
+        for line in text.lines() {
+            if line.starts_with("# revision: ") {
+                manifest.revision = Some(line["# revision: ".len()..].trim().to_string());
+            } else if !line.starts_with('#') {
+                if let Some((hash, path)) = parse_hash_line(line) {
+                    manifest.hashes.insert(path, hash);
+                }
+            }
+        }
+        manifest
+    }
+}
+
+/// "<sha256>  ./path" as written by sha256sum
+fn parse_hash_line(line: &str) -> Option<(String, String)> {
+    if line.len() < 67 || !line.is_char_boundary(64) {
+        return None;
+    }
+    let (hash, rest) = line.split_at(64);
+    // a space and then " " or "*" for the text or binary mode
+    let path = &rest[2..];
+    let path = path.trim_start_matches("./");
+    if path == "-" || path.is_empty() {
+        return None;
+    }
+    Some((hash.to_lowercase(), path.to_string()))
+}
+
+/// hex encoded sha256 of a file
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn excludes(project: &Project) -> Vec<Regex> {
+    match project.exclude {
+        None => Vec::new(),
+        Some(ref exclude) => exclude.iter().filter_map(|pattern| create_re(pattern)).collect(),
+    }
+}
+
+/// match against "/relative/path" so that patterns starting with "/" are
+/// anchored at the project root like rsync does
+fn is_excluded(relative: &str, excludes: &[Regex]) -> bool {
+    is_exclude(&Path::new("/").join(relative), excludes)
+}
+
+fn walk(root: &Path, dir: &Path, excludes: &[Regex], follow_links: bool, hashes: &mut Hashes) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let relative = path.strip_prefix(root)?.to_string_lossy().into_owned();
+        if is_excluded(&relative, excludes) {
+            continue;
+        }
+        // symlinks are followed only if the files they point to are synced
+        let metadata = if follow_links { fs::metadata(&path) } else { fs::symlink_metadata(&path) };
+        let file_type = match metadata {
+            Ok(metadata) => metadata.file_type(),
+            // a dangling symlink
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            walk(root, &path, excludes, follow_links, hashes)?;
+        } else if file_type.is_file() {
+            hashes.insert(relative, hash_file(&path)?);
+        }
+    }
+    Ok(())
+}
+
+/// the hashes of the files in src which are synced, selected like the sync
+/// does
+pub fn local_hashes(project: &Project) -> Result<Hashes> {
+    let src = Path::new(&project.src);
+    if !src.is_dir() {
+        bail!("project {}: src {:?} is not a directory", project.name, project.src);
+    }
+    let mut hashes = Hashes::new();
+    let excludes = excludes(project);
+    let follow_links = project.transfer.as_ref().and_then(|t| t.symlinks) == Some(SymlinkPolicy::Copy);
+    if project.gitignore == Some(true) || project.filter_file.is_some()
+        || project.rsync_args.as_ref().map_or(false, |args| !args.is_empty())
+    {
+        // only rsync knows what these filters select
+        for relative in rsync::selected_files(project)? {
+            let path = src.join(&relative);
+            let metadata = if follow_links { fs::metadata(&path) } else { fs::symlink_metadata(&path) };
+            if metadata.map(|m| m.file_type().is_file()).unwrap_or(false) {
+                hashes.insert(relative, hash_file(&path)?);
+            }
+        }
+    } else if project.source == Some(SourceMode::GitTracked) {
+        for relative in git::tracked_files(src)? {
+            let path = src.join(&relative);
+            // a tracked file may be deleted in the work tree
+            let is_file = fs::symlink_metadata(&path).map(|m| m.file_type().is_file()).unwrap_or(false);
+            if is_file && !is_excluded(&relative, &excludes) {
+                hashes.insert(relative, hash_file(&path)?);
+            }
+        }
+    } else {
+        walk(src, src, &excludes, follow_links, &mut hashes)?;
+    }
+    Ok(hashes)
+}
+
+fn manifest_path(project: &Project) -> String {
+    format!("{}/{}", project.dest.trim_end_matches('/'), MANIFEST_FILE)
+}
+
+/// write the manifest of the synced files to `dest`
+pub fn upload(host: &Host, project: &Project) -> Result<()> {
+    let manifest = Manifest {
+        revision: git::revision(&project.src),
+        hashes: local_hashes(project)?,
+    };
+    let command = format!("cat > {}", ssh::quote(&manifest_path(project)));
+    ssh::exec(host, &command, Some(manifest.render().as_bytes()))?;
+    debug!("manifest of {} written to {}", project.name, manifest_path(project));
+    Ok(())
+}
+
+/// the manifest written by the last sync, if any
+fn remote_manifest(host: &Host, project: &Project) -> Result<Option<Manifest>> {
+    let command = format!("cat {} 2>/dev/null || true", ssh::quote(&manifest_path(project)));
+    let text = ssh::exec(host, &command, None)?;
+    if text.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Manifest::parse(&text)))
+}
+
+/// hash the files in dest on the remote
+fn remote_hashes(host: &Host, project: &Project) -> Result<Hashes> {
+    let command = format!(
+        "cd {} && if command -v sha256sum >/dev/null 2>&1; then h=sha256sum; else h='shasum -a 256'; fi && \
+         find . -type f ! -path './{}/*' ! -path './{}' -print0 | xargs -0 $h",
+        ssh::quote(&project.dest),
+        PARTIAL_DIR,
+        MANIFEST_FILE
+    );
+    let output = ssh::exec(host, &command, None)?;
+    let excludes = excludes(project);
+    Ok(output
+        .lines()
+        .filter_map(parse_hash_line)
+        // excluded files are not touched by rsync, they are not part of the deploy
+        .filter(|&(_, ref path)| !is_excluded(path, &excludes))
+        .map(|(hash, path)| (path, hash))
+        .collect())
+}
+
+/// how the files in dest differ from src
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct VerifyReport {
+    pub project: String,
+    pub host: String,
+    /// the git revision of src when the manifest was written
+    pub revision: Option<String>,
+    /// the files whose content differs from src
+    pub drifted: Vec<String>,
+    /// the files in src which are not in dest
+    pub missing: Vec<String>,
+    /// the files in dest which are not in src
+    pub extra: Vec<String>,
+    /// the files changed on the remote since the manifest was written
+    pub modified_remote: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.drifted.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+fn compare(local: &Hashes, remote: &Hashes, manifest: Option<&Manifest>, report: &mut VerifyReport) {
+    for (path, hash) in local.iter() {
+        match remote.get(path) {
+            None => report.missing.push(path.clone()),
+            Some(remote_hash) if remote_hash != hash => report.drifted.push(path.clone()),
+            _ => {}
+        }
+    }
+    for path in remote.keys() {
+        if !local.contains_key(path) {
+            report.extra.push(path.clone());
+        }
+    }
+    if let Some(manifest) = manifest {
+        report.revision = manifest.revision.clone();
+        for (path, hash) in remote.iter() {
+            if manifest.hashes.get(path).map_or(true, |deployed| deployed != hash) {
+                report.modified_remote.push(path.clone());
+            }
+        }
+    }
+}
+
+/// recompute the hashes on the remote and compare them with src
+pub fn verify(host: &Host, project: &Project) -> Result<VerifyReport> {
+    // in release mode the deployed files are in dest/current
+    let mut deployed = project.clone();
+    deployed.dest = release::active_dest(project);
+    let local = local_hashes(project)?;
+    let remote = remote_hashes(host, &deployed)?;
+    let manifest = remote_manifest(host, &deployed)?;
+    let mut report = VerifyReport {
+        project: project.name.clone(),
+        host: host.hostname.clone(),
+        ..Default::default()
+    };
+    compare(&local, &remote, manifest.as_ref(), &mut report);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_A: &str = "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb";
+    const HASH_B: &str = "3e23e8160039594a33894f6564e1b1348bbd7a0088d42c4acb73eeaed59c009d";
+
+    #[test]
+    fn test_manifest() {
+        let mut hashes = Hashes::new();
+        hashes.insert("a".to_string(), HASH_A.to_string());
+        hashes.insert("dir/b c".to_string(), HASH_B.to_string());
+        let manifest = Manifest {
+            revision: Some("0123abcd".to_string()),
+            hashes,
+        };
+        assert_eq!(Manifest::parse(&manifest.render()), manifest);
+    }
+
+    #[test]
+    fn test_parse_hash_line() {
+        let line = format!("{}  ./dir/b c", HASH_B);
+        assert_eq!(parse_hash_line(&line), Some((HASH_B.to_string(), "dir/b c".to_string())));
+        let line = format!("{} *a", HASH_A);
+        assert_eq!(parse_hash_line(&line), Some((HASH_A.to_string(), "a".to_string())));
+        assert_eq!(parse_hash_line(&format!("{}  -", HASH_A)), None);
+        assert_eq!(parse_hash_line("sha256sum: error"), None);
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut local = Hashes::new();
+        local.insert("same".to_string(), HASH_A.to_string());
+        local.insert("changed".to_string(), HASH_A.to_string());
+        local.insert("missing".to_string(), HASH_A.to_string());
+        let mut remote = Hashes::new();
+        remote.insert("same".to_string(), HASH_A.to_string());
+        remote.insert("changed".to_string(), HASH_B.to_string());
+        remote.insert("extra".to_string(), HASH_A.to_string());
+        let manifest = Manifest {
+            revision: None,
+            hashes: local.clone(),
+        };
+
+        let mut report = VerifyReport::default();
+        compare(&local, &remote, Some(&manifest), &mut report);
+        assert_eq!(report.drifted, vec!["changed".to_string()]);
+        assert_eq!(report.missing, vec!["missing".to_string()]);
+        assert_eq!(report.extra, vec!["extra".to_string()]);
+        assert_eq!(report.modified_remote, vec!["changed".to_string(), "extra".to_string()]);
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn test_hash_file() {
+        let path = Path::new("/tmp/test_hash_file.txt");
+        fs::write(path, "a").unwrap();
+        assert_eq!(hash_file(path).unwrap(), HASH_A);
+    }
+
+    #[test]
+    #[ignore = "needs rsync, run with cargo test -- --ignored"]
+    fn test_local_hashes_gitignore() {
+        let dir = Path::new("/tmp").join(format!("rn_manifest_gitignore_{}", ::std::process::id()));
+        fs::create_dir_all(dir.join("logs")).unwrap();
+        fs::write(dir.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("logs/x.log"), "x").unwrap();
+        let project = Project {
+            name: "web".to_string(),
+            src: dir.to_string_lossy().into_owned(),
+            dest: "/srv/web".to_string(),
+            gitignore: Some(true),
+            ..Default::default()
+        };
+        let hashes = local_hashes(&project).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(hashes.keys().collect::<Vec<_>>(), vec![".gitignore", "a"]);
+        assert_eq!(hashes["a"], HASH_A);
+    }
+}
@@ -1,5 +1,20 @@
 pub mod sshconfig;
 pub mod toml_parser;
+pub mod config;
+pub mod validate;
 pub mod util;
 pub mod watchdog;
 pub mod rsync;
+pub mod transfer;
+pub mod signal;
+pub mod output;
+pub mod progress;
+pub mod history;
+pub mod git;
+pub mod ssh;
+pub mod manifest;
+pub mod release;
+pub mod wire;
+pub mod agent;
+pub mod delta;
+pub mod container;
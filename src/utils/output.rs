@@ -0,0 +1,104 @@
+use errors::*;
+use serde_json;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::history::Record;
+use super::manifest::VerifyReport;
+use super::rsync::SyncReport;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// how the results are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// human readable, through the logger
+    Text,
+    /// one json object per line
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("unknown output format: {}", s),
+        }
+    }
+}
+
+pub fn set_format(format: OutputFormat) {
+    JSON.store(format == OutputFormat::Json, Ordering::SeqCst);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+/// the records of the json output
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// a sync has finished
+    Sync(&'a SyncReport),
+    /// files changed in watch mode
+    Change { project: &'a str, paths: Vec<String> },
+    /// the settings were reloaded in watch mode
+    Reload { project: &'a str },
+    Error { project: &'a str, host: &'a str, message: String },
+    /// a record of `rn history`
+    History(&'a Record),
+    /// the result of `rn verify`
+    Verify(&'a VerifyReport),
+}
+
+/// write the event to stdout as a line of json, does nothing in text mode
+pub fn emit(event: &Event) {
+    if !is_json() {
+        return;
+    }
+    match serde_json::to_string(event) {
+        Ok(line) => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            // flush every line, the consumer may be waiting for it
+            if let Err(e) = writeln!(stdout, "{}", line).and_then(|_| stdout.flush()) {
+                error!("write json output failed: {}", e);
+            }
+        }
+        Err(e) => error!("serialize {:?} failed: {}", event, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_json() {
+        let report = SyncReport {
+            project: "test".to_string(),
+            host: "ubuntu".to_string(),
+            files: vec!["a.txt".to_string()],
+            bytes: 12,
+            ..Default::default()
+        };
+        let line = serde_json::to_string(&Event::Sync(&report)).unwrap();
+        assert!(line.starts_with(r#"{"event":"sync","project":"test","host":"ubuntu","files":["a.txt"]"#));
+
+        let line = serde_json::to_string(&Event::Change {
+            project: "test",
+            paths: vec!["/tmp/a.txt".to_string()],
+        }).unwrap();
+        assert_eq!(line, r#"{"event":"change","project":"test","paths":["/tmp/a.txt"]}"#);
+    }
+
+    #[test]
+    fn test_output_format() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -0,0 +1,140 @@
+extern crate libc;
+
+use std::io::{self, Read, Write};
+use super::output;
+
+const BAR_WIDTH: usize = 30;
+
+/// a line of `rsync --info=progress2`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub bytes: u64,
+    pub percent: u8,
+    /// e.g. 118.05MB/s
+    pub rate: String,
+    /// the remaining time while transferring, the total time at the end
+    pub eta: String,
+}
+
+/// parse "      1,238,099  44%  118.05MB/s    0:00:00 (xfr#1, to-chk=2/4)"
+pub fn parse_progress(line: &str) -> Option<Progress> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens.len() < 4 || !tokens[1].ends_with('%') || !tokens[2].ends_with("/s") {
+        return None;
+    }
+    let bytes = tokens[0].replace(",", "").parse().ok()?;
+    let percent = tokens[1].trim_end_matches('%').parse().ok()?;
+    Some(Progress {
+        bytes,
+        percent,
+        rate: tokens[2].to_string(),
+        eta: tokens[3].to_string(),
+    })
+}
+
+/// draw a progress bar on stderr if it is a terminal
+pub struct ProgressBar {
+    enabled: bool,
+    drawn: bool,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        ProgressBar {
+            enabled: !output::is_json() && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1,
+            drawn: false,
+        }
+    }
+
+    pub fn update(&mut self, progress: &Progress) {
+        if !self.enabled {
+            return;
+        }
+        let done = BAR_WIDTH * progress.percent.min(100) as usize / 100;
+        let bar = format!("{}{}", "#".repeat(done), "-".repeat(BAR_WIDTH - done));
+        let _ = write!(
+            io::stderr(),
+            "\r[{}] {:>3}%  {:>12}  ETA {}",
+            bar,
+            progress.percent,
+            progress.rate,
+            progress.eta
+        );
+        self.drawn = true;
+    }
+
+    /// clear the bar so that it does not mix with the following logs
+    pub fn finish(&mut self) {
+        if self.drawn {
+            let _ = write!(io::stderr(), "\r\x1b[K");
+            self.drawn = false;
+        }
+    }
+}
+
+/// read the stdout of rsync as it comes: the progress lines are drawn as a bar,
+/// the other lines are returned
+pub fn read_stdout<R: Read>(mut pipe: R) -> String {
+    let mut bar = ProgressBar::new();
+    let mut output = String::new();
+    let mut line: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 4096];
+    {
+        // progress2 ends its updates with "\r" and the other lines with "\n"
+        let mut handle_line = |line: &mut Vec<u8>| {
+            let text = String::from_utf8_lossy(line).into_owned();
+            line.clear();
+            match parse_progress(&text) {
+                Some(progress) => bar.update(&progress),
+                None if text.trim().len() > 0 => {
+                    output.push_str(&text);
+                    output.push('\n');
+                }
+                None => {}
+            }
+        };
+        loop {
+            let n = match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            for byte in buf[..n].iter() {
+                match *byte {
+                    b'\r' | b'\n' => handle_line(&mut line),
+                    _ => line.push(*byte),
+                }
+            }
+        }
+        handle_line(&mut line);
+    }
+    bar.finish();
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_progress() {
+        let progress = parse_progress("      1,238,099  44%  118.05MB/s    0:00:12 (xfr#1, to-chk=2/4)");
+        assert_eq!(
+            progress,
+            Some(Progress {
+                bytes: 1238099,
+                percent: 44,
+                rate: "118.05MB/s".to_string(),
+                eta: "0:00:12".to_string(),
+            })
+        );
+        assert_eq!(parse_progress("send 12 a/b.txt"), None);
+        assert_eq!(parse_progress("sent 1,234 bytes  received 35 bytes  2,538.00 bytes/sec"), None);
+    }
+
+    #[test]
+    fn test_read_stdout() {
+        let stdout = "send 12 a.txt\r          0   0%    0.00kB/s    0:00:00\r         12 100%   11.72kB/s    0:00:00 (xfr#1, to-chk=0/2)\nsent 120 bytes\n";
+        let output = read_stdout(stdout.as_bytes());
+        assert_eq!(output, "send 12 a.txt\nsent 120 bytes\n");
+    }
+}
@@ -0,0 +1,246 @@
+use errors::*;
+use chrono::Utc;
+use super::{rsync, ssh};
+use super::rsync::SyncReport;
+use super::sshconfig::Host;
+use super::toml_parser::{DeployMode, Project};
+
+/// the releases are kept in dest/RELEASES_DIR
+const RELEASES_DIR: &str = "releases";
+/// the symlink in dest pointing to the active release
+const CURRENT_LINK: &str = "current";
+const DEFAULT_KEEP_RELEASES: usize = 5;
+
+fn dest_root(project: &Project) -> &str {
+    project.dest.trim_end_matches('/')
+}
+
+/// where the synced files are served from: dest/current in release mode, dest otherwise
+pub fn active_dest(project: &Project) -> String {
+    match project.deploy_mode {
+        Some(DeployMode::Release) => format!("{}/{}", dest_root(project), CURRENT_LINK),
+        _ => project.dest.clone(),
+    }
+}
+
+/// the markers before the names of the releases and the target of `current`
+/// in the output of the command listing them
+const RELEASES_MARKER: &str = "--rn-releases--";
+const CURRENT_MARKER: &str = "--rn-current--";
+
+/// the names of the releases on the host, oldest first, and the one `current` points to
+fn list_releases(host: &Host, project: &Project) -> Result<(Vec<String>, Option<String>)> {
+    let dest = ssh::quote(dest_root(project));
+    let command = format!(
+        "mkdir -p {dest}/{releases} && echo {releases_marker} && ls -1 {dest}/{releases} && echo {current_marker} && (readlink {dest}/{current} || true)",
+        dest = dest,
+        releases = RELEASES_DIR,
+        current = CURRENT_LINK,
+        releases_marker = RELEASES_MARKER,
+        current_marker = CURRENT_MARKER
+    );
+    parse_releases(&ssh::exec(host, &command, None)?)
+}
+
+/// the releases and current from the output of the command of list_releases,
+/// current is None if it does not point to one of the releases
+fn parse_releases(output: &str) -> Result<(Vec<String>, Option<String>)> {
+    let mut lines = output.lines().skip_while(|line| line.trim() != RELEASES_MARKER);
+    if lines.next().is_none() {
+        bail!("list the releases failed: {}", output.trim());
+    }
+    let mut releases = Vec::new();
+    let mut link = None;
+    let mut in_current = false;
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+        if line.trim() == CURRENT_MARKER {
+            in_current = true;
+        } else if in_current {
+            link = Some(line.trim().to_string());
+        } else {
+            releases.push(line.to_string());
+        }
+    }
+    if !in_current {
+        bail!("list the releases failed: {}", output.trim());
+    }
+    releases.sort();
+    let current = link.and_then(|link| {
+        let name = link.trim_end_matches('/').trim_start_matches(&format!("{}/", RELEASES_DIR)).to_string();
+        if releases.contains(&name) {
+            Some(name)
+        } else {
+            warn!("{} points to {}, which is not a release", CURRENT_LINK, link);
+            None
+        }
+    });
+    Ok((releases, current))
+}
+
+/// point dest/current to the release, replacing the link atomically
+fn switch_to(host: &Host, project: &Project, release: &str) -> Result<()> {
+    // GNU mv needs -T, BSD mv -h, to replace the link instead of moving into it
+    let command = format!(
+        "cd {} && ln -sfn {}/{} .rn-current && (mv -Tf .rn-current {current} 2>/dev/null || mv -fh .rn-current {current})",
+        ssh::quote(dest_root(project)),
+        RELEASES_DIR,
+        ssh::quote(release),
+        current = CURRENT_LINK
+    );
+    ssh::exec(host, &command, None)?;
+    info!("{}: {}/{} now points to {}", project.name, dest_root(project), CURRENT_LINK, release);
+    Ok(())
+}
+
+fn remove_releases(host: &Host, project: &Project, releases: &[String]) -> Result<()> {
+    if releases.is_empty() {
+        return Ok(());
+    }
+    let paths: Vec<String> = releases
+        .iter()
+        .map(|release| ssh::quote(&format!("{}/{}/{}", dest_root(project), RELEASES_DIR, release)))
+        .collect();
+    ssh::exec(host, &format!("rm -rf {}", paths.join(" ")), None)?;
+    debug!("removed releases: {:?}", releases);
+    Ok(())
+}
+
+/// the oldest releases beyond the `keep` newest ones, never the current one
+fn releases_to_remove(releases: &[String], keep: usize, current: Option<&str>) -> Vec<String> {
+    let count = releases.len().saturating_sub(keep);
+    releases[..count]
+        .iter()
+        .filter(|release| Some(release.as_str()) != current)
+        .cloned()
+        .collect()
+}
+
+/// the release before the current one
+fn previous_release(releases: &[String], current: Option<&str>) -> Option<String> {
+    let index = match current {
+        Some(current) => releases.iter().position(|release| release == current)?,
+        None => releases.len(),
+    };
+    if index == 0 {
+        return None;
+    }
+    releases.get(index - 1).cloned()
+}
+
+/// sync into a new release, hard linking the unchanged files from the
+/// previous one, then switch `current` to it and remove the old releases
+pub fn deploy(host: &Host, project: &Project) -> Result<SyncReport> {
+    if project.since.is_some() {
+        // a release must hold all the files, not only the changed ones
+        bail!("project {}: --since can not be used with deploy_mode = \"release\"", project.name);
+    }
+    let (releases, current) = list_releases(host, project)?;
+    // in UTC, the names are sorted to find the newest release and local time
+    // repeats an hour when DST ends
+    let name = Utc::now().format("%Y%m%d%H%M%S%3f").to_string();
+    let mut release = project.clone();
+    release.dest = format!("{}/{}/{}/", dest_root(project), RELEASES_DIR, name);
+
+    let mut extra_args = Vec::new();
+    if let Some(previous) = current.clone().or_else(|| releases.last().cloned()) {
+        // relative to the new release
+        extra_args.push(format!("--link-dest=../{}", previous));
+    }
+    let report = match rsync::transfer(host, &release, false, &extra_args) {
+        Ok(report) => report,
+        Err(e) => {
+            remove_releases(host, project, &[name.clone()])?;
+            return Err(e).chain_err(|| format!("sync release {} failed, {} still points to {:?}", name, CURRENT_LINK, current));
+        }
+    };
+    switch_to(host, project, &name)?;
+
+    let mut releases = releases;
+    releases.push(name.clone());
+    let keep = project.keep_releases.unwrap_or(DEFAULT_KEEP_RELEASES);
+    remove_releases(host, project, &releases_to_remove(&releases, keep, Some(&name)))?;
+    Ok(report)
+}
+
+/// point `current` back to the previous release
+pub fn rollback(host: &Host, project: &Project) -> Result<String> {
+    if project.deploy_mode != Some(DeployMode::Release) {
+        bail!("project {} does not use deploy_mode = \"release\"", project.name);
+    }
+    let (releases, current) = list_releases(host, project)?;
+    let previous = match previous_release(&releases, current.as_ref().map(|c| c.as_str())) {
+        Some(previous) => previous,
+        None => bail!("no release before {:?} to roll back to", current),
+    };
+    switch_to(host, project, &previous)?;
+    Ok(previous)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_releases_to_remove() {
+        let releases = names(&["1", "2", "3", "4"]);
+        assert_eq!(releases_to_remove(&releases, 2, Some("4")), names(&["1", "2"]));
+        // the current release is kept even if it is old, e.g. after a rollback
+        assert_eq!(releases_to_remove(&releases, 2, Some("1")), names(&["2"]));
+        assert!(releases_to_remove(&releases, 5, Some("4")).is_empty());
+    }
+
+    #[test]
+    fn test_previous_release() {
+        let releases = names(&["1", "2", "3"]);
+        assert_eq!(previous_release(&releases, Some("3")), Some("2".to_string()));
+        assert_eq!(previous_release(&releases, Some("1")), None);
+        assert_eq!(previous_release(&releases, None), Some("3".to_string()));
+        assert_eq!(previous_release(&releases, Some("0")), None);
+    }
+
+    #[test]
+    fn test_parse_releases() {
+        let output = format!("{}\n2\n1\n{}\nreleases/2\n", RELEASES_MARKER, CURRENT_MARKER);
+        assert_eq!(parse_releases(&output).unwrap(), (names(&["1", "2"]), Some("2".to_string())));
+        // current left pointing to a release which is gone
+        let output = format!("{}\n{}\nreleases/2\n", RELEASES_MARKER, CURRENT_MARKER);
+        assert_eq!(parse_releases(&output).unwrap(), (Vec::new(), None));
+        let output = format!("{}\n1\n{}\n", RELEASES_MARKER, CURRENT_MARKER);
+        assert_eq!(parse_releases(&output).unwrap(), (names(&["1"]), None));
+        assert!(parse_releases("1\n").is_err());
+    }
+
+    #[test]
+    fn test_list_releases() {
+        let dir = Path::new("/tmp").join(format!("rn_releases_{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let project = Project {
+            name: "web".to_string(),
+            src: "/tmp/src".to_string(),
+            dest: format!("{}/", dir.display()),
+            deploy_mode: Some(DeployMode::Release),
+            ..Default::default()
+        };
+        let host = Host::local();
+        assert_eq!(list_releases(&host, &project).unwrap(), (Vec::new(), None));
+
+        fs::create_dir_all(dir.join("releases/20200101000000000")).unwrap();
+        fs::create_dir_all(dir.join("releases/20200102000000000")).unwrap();
+        symlink("releases/20200101000000000", dir.join("current")).unwrap();
+        assert_eq!(
+            list_releases(&host, &project).unwrap(),
+            (names(&["20200101000000000", "20200102000000000"]), Some("20200101000000000".to_string()))
+        );
+
+        fs::remove_dir_all(dir.join("releases")).unwrap();
+        assert_eq!(list_releases(&host, &project).unwrap(), (Vec::new(), None));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,12 +1,187 @@
-use std::{io, fs};
+extern crate libc;
+
+use std::collections::HashSet;
+use std::{io, fs, process, thread};
+use std::io::{Read, Write};
 use std::path::Path;
-use std::process::Command;
-use super::sshconfig::servername2ip;
+use std::process::{Command, Child, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use errors::*;
+use slog_scope;
+use super::{git, manifest, output, progress, release, signal, ssh};
 use super::sshconfig::Host;
-use super::toml_parser::Project;
+use super::util::{ignore_interrupt, runtime_dir};
+use super::toml_parser::{DeployMode, Project, SourceMode};
+use super::transfer::SymlinkPolicy;
+
+/// partially transferred files are kept here on the remote instead of at their real path
+pub const PARTIAL_DIR: &str = ".rn-partial";
+/// how long a running transfer may continue after a shutdown signal
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long a cancelled transfer may take to exit before it is killed
+const KILL_TIMEOUT: Duration = Duration::from_secs(2);
+/// one line for each transferred or deleted file: operation, file length, name
+const OUT_FORMAT: &str = "%o %l %n";
+
+/// the summary of `rsync --stats`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncStats {
+    /// the files in the source
+    pub files: u64,
+    pub files_transferred: u64,
+    /// the size of the files in the source
+    pub total_size: u64,
+    /// the size of the transferred files
+    pub transferred_size: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub speedup: f64,
+}
+
+/// what a sync has done
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SyncReport {
+    pub project: String,
+    pub host: String,
+    /// the transferred files
+    pub files: Vec<String>,
+    /// the files deleted on the remote
+    pub deleted: Vec<String>,
+    /// the bytes sent
+    pub bytes: u64,
+    /// in seconds
+    pub duration: f64,
+    pub errors: Vec<String>,
+    pub stats: SyncStats,
+}
+
+/// "Total bytes sent: 1,450" -> 1450
+fn stat_number(line: &str, name: &str) -> Option<u64> {
+    if !line.starts_with(name) {
+        return None;
+    }
+    line[name.len()..]
+        .split_whitespace()
+        .next()
+        .and_then(|number| number.replace(",", "").parse().ok())
+}
+
+/// fill the report from the stdout of `rsync -v --stats --out-format=OUT_FORMAT`
+fn parse_output(stdout: &str, report: &mut SyncReport) {
+    for line in stdout.lines() {
+        let stats = &mut report.stats;
+        if let Some(n) = stat_number(line, "Number of files:") {
+            stats.files = n;
+            continue;
+        }
+        // "Number of files transferred" before rsync 3.1
+        if let Some(n) = stat_number(line, "Number of regular files transferred:")
+            .or_else(|| stat_number(line, "Number of files transferred:")) {
+            stats.files_transferred = n;
+            continue;
+        }
+        if let Some(n) = stat_number(line, "Total file size:") {
+            stats.total_size = n;
+            continue;
+        }
+        if let Some(n) = stat_number(line, "Total transferred file size:") {
+            stats.transferred_size = n;
+            continue;
+        }
+        if let Some(n) = stat_number(line, "Total bytes sent:") {
+            stats.bytes_sent = n;
+            continue;
+        }
+        if let Some(n) = stat_number(line, "Total bytes received:") {
+            stats.bytes_received = n;
+            continue;
+        }
+        if line.starts_with("total size is ") {
+            // total size is 1,234  speedup is 0.83
+            if let Some(speedup) = line.split("speedup is ").nth(1) {
+                stats.speedup = speedup.split_whitespace().next().and_then(|s| s.parse().ok()).unwrap_or(0.0);
+            }
+            continue;
+        }
+        let parts: Vec<&str> = line.splitn(3, ' ').collect();
+        if parts.len() == 3 && parts[1].parse::<u64>().is_ok() {
+            match parts[0] {
+                // directories end with "/" and are not counted as files
+                "send" | "recv" if !parts[2].ends_with("/") => report.files.push(parts[2].to_string()),
+                "del." => report.deleted.push(parts[2].trim_end_matches('/').to_string()),
+                _ => {}
+            }
+        } else if line.starts_with("sent ") {
+            // sent 1,234 bytes  received 35 bytes  2,538.00 bytes/sec
+            if let Some(bytes) = line.split_whitespace().nth(1) {
+                report.bytes = bytes.replace(",", "").parse().unwrap_or(0);
+            }
+        }
+    }
+    if report.bytes == 0 {
+        report.bytes = report.stats.bytes_sent;
+    }
+}
+
+/// sync src to dest, or to a new release in dest for `deploy_mode = "release"`
+pub fn sync(host: &Host, project: &Project, delete: bool) -> Result<SyncReport> {
+    if project.deploy_mode == Some(DeployMode::Release) {
+        return release::deploy(host, project);
+    }
+    transfer(host, project, delete, &[])
+}
+
+/// the files to sync if not all of src, relative to src
+fn file_list(project: &Project) -> Result<Option<Vec<String>>> {
+    let src = Path::new(&project.src);
+    let tracked = match project.source {
+        Some(SourceMode::GitTracked) => Some(git::tracked_files(src)?),
+        _ => None,
+    };
+    let changed = match project.since {
+        Some(ref since) => Some(git::changed_files(src, since)?),
+        None => None,
+    };
+    let files = match (tracked, changed) {
+        (Some(tracked), Some(changed)) => {
+            let tracked: HashSet<String> = tracked.into_iter().collect();
+            Some(changed.into_iter().filter(|path| tracked.contains(path)).collect())
+        }
+        (tracked, changed) => tracked.or(changed),
+    };
+    // the deleted files can not be sent
+    Ok(files.map(|files: Vec<String>| files.into_iter().filter(|path| src.join(path).is_file()).collect()))
+}
+
+/// the settings the tar fallback of a container can not apply. compress and
+/// bwlimit are left out: the profile of every host sets them, and they do
+/// not change the files written; neither does checksum, tar sends them all
+fn unsupported_without_rsync(project: &Project, delete: bool) -> Vec<&'static str> {
+    let transfer = project.transfer.clone().unwrap_or_default();
+    let set = [
+        ("--delete", delete),
+        ("transfer.archive", transfer.archive.is_some()),
+        ("transfer.perms", transfer.perms.is_some()),
+        ("transfer.owner", transfer.owner.is_some()),
+        ("transfer.group", transfer.group.is_some()),
+        // tar keeps the symlinks
+        ("transfer.symlinks", transfer.symlinks.map_or(false, |symlinks| symlinks != SymlinkPolicy::Preserve)),
+        ("transfer.chmod", transfer.chmod.is_some()),
+        ("transfer.chown", transfer.chown.is_some()),
+        ("transfer.xattrs", transfer.xattrs.is_some()),
+        ("transfer.acls", transfer.acls.is_some()),
+        ("rsync_args", project.rsync_args.as_ref().map_or(false, |args| !args.is_empty())),
+        ("filter_file", project.filter_file.is_some()),
+        ("gitignore", project.gitignore == Some(true)),
+    ];
+    set.iter().filter(|&&(_, set)| set).map(|&(name, _)| name).collect()
+}
 
-pub fn sync(host: &Host, project: &Project, delete: bool) -> io::Result<()> {
-    let username = &host.user;
+/// run rsync from src to dest with some extra arguments, fails with
+/// `ErrorKind::SyncFailed` if anything went wrong during the transfer
+pub fn transfer(host: &Host, project: &Project, delete: bool, extra_args: &[String]) -> Result<SyncReport> {
+    let started = Instant::now();
     let path = Path::new(project.src.as_str());
     let file_type = fs::metadata(path)?.file_type();
     // if the source file is directory and not ends with "/", we should add it.
@@ -15,57 +190,301 @@ pub fn sync(host: &Host, project: &Project, delete: bool) -> io::Result<()> {
         source.push_str("/")
     }
     debug!("source file is {:?}", source);
-    let remote_ip = servername2ip(host.hostname.as_str());
-
-    let login_strings: String;
-    match host.identityfile {
-        None => {
-            match host.password {
-                None => {
-                    panic!("no password or identifile was set!");
-                }
-                Some(ref password) => {
-                    login_strings = format!(
-                        r#"sshpass -p {} ssh  -l {} -p {} -o "ConnectTimeout=2""#,
-                        password,
-                        username,
-                        host.port
-                    );
-                }
+    let files = file_list(project)?;
+    if files.is_some() && !file_type.is_dir() {
+        bail!("project {}: src must be a directory to sync a list of files", project.name);
+    }
+
+    let mut report = match host.container {
+        Some(ref container) if !container.has_rsync()? => {
+            if !extra_args.is_empty() {
+                bail!("project {}: rsync is not installed in {}", project.name, container.name);
+            }
+            let unsupported = unsupported_without_rsync(project, delete);
+            if !unsupported.is_empty() {
+                bail!(
+                    "project {}: rsync is not installed in {}, {} can not be used without it",
+                    project.name,
+                    container.name,
+                    unsupported.join(", ")
+                );
             }
+            warn!("rsync is not installed in {}, all the files are sent with tar", container.name);
+            container.copy(host, project, files.as_ref().map(|files| files.as_slice()))?
         }
-        Some(ref path) => {
-            let path = path.to_str().unwrap();
-            login_strings = format!(r#"ssh -i {} -p {} -o "UserKnownHostsFile=/dev/null" -o "StrictHostKeyChecking no" -o "ConnectTimeout=2""#,
-                path,
-                host.port
-            );
+        _ => rsync(host, project, source, files, delete, extra_args)?,
+    };
+    report.project = project.name.clone();
+    report.host = host.hostname.clone();
+    if project.manifest == Some(true) && report.errors.is_empty() {
+        if let Err(e) = manifest::upload(host, project) {
+            warn!("write manifest failed: {}", e);
+            report.errors.push(format!("write manifest failed: {}", e));
         }
     }
+    let elapsed = started.elapsed();
+    report.duration = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    info!(
+        "{}: {} files transferred, {} deleted, {} bytes sent in {:.1}s, speedup is {}",
+        report.project,
+        report.stats.files_transferred,
+        report.deleted.len(),
+        report.bytes,
+        report.duration,
+        report.stats.speedup
+    );
+    if !report.errors.is_empty() {
+        bail!(ErrorKind::SyncFailed(Box::new(report)));
+    }
+    Ok(report)
+}
+
+/// the rsync options deciding which files of src are synced
+fn selection_args(project: &Project) -> Vec<String> {
+    let mut args = Vec::new();
+    if project.gitignore == Some(true) {
+        // read the .gitignore of each directory, like git does
+        args.push("--filter=:- .gitignore".to_string());
+    }
+    if let Some(ref exclude) = project.exclude {
+        for pattern in exclude.iter() {
+            args.push("--exclude".to_string());
+            args.push(pattern.clone());
+        }
+    }
+    if let Some(ref filter_file) = project.filter_file {
+        args.push(format!("--filter=merge {}", filter_file));
+    }
+    if let Some(ref rsync_args) = project.rsync_args {
+        args.extend(rsync_args.iter().cloned());
+    }
+    args
+}
+
+/// the files of src a sync sends, relative to src, as rsync selects them
+/// with a dry run into an empty directory
+pub fn selected_files(project: &Project) -> Result<Vec<String>> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let files = file_list(project)?;
+    let mut source = project.src.clone();
+    if !source.ends_with('/') {
+        source.push('/');
+    }
+    let empty = runtime_dir()?.join(format!("select-{}-{}", process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+    fs::create_dir_all(&empty)?;
+    let mut cmd = Command::new("rsync");
+    cmd.arg("--dry-run")
+        .arg("--recursive")
+        .args(project.transfer.clone().unwrap_or_default().rsync_args())
+        .arg("--out-format=%n");
+    if files.is_some() {
+        cmd.arg("--files-from=-").arg("--from0").stdin(Stdio::piped());
+    }
+    cmd.args(selection_args(project)).arg(&source).arg(&empty);
+    debug!("{:?}", cmd);
+    let result = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().and_then(|mut child| {
+        // written from a thread as in rsync(), rsync prints while it reads
+        let stdin = child.stdin.take();
+        let writer = spawn_scoped(move || -> io::Result<()> {
+            if let (Some(mut pipe), Some(files)) = (stdin, files) {
+                for file in files.iter() {
+                    pipe.write_all(file.as_bytes())?;
+                    pipe.write_all(b"\0")?;
+                }
+            }
+            Ok(())
+        });
+        let output = child.wait_with_output();
+        let _ = writer.join();
+        output
+    });
+    let _ = fs::remove_dir_all(&empty);
+    let output = result.chain_err(|| "run rsync failed")?;
+    if !output.status.success() {
+        bail!("list the files of {} failed: {}", project.name, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        // the directories end with "/"
+        .filter(|line| !line.is_empty() && !line.ends_with('/'))
+        .map(String::from)
+        .collect())
+}
+
+/// run rsync from source to dest of the project
+fn rsync(
+    host: &Host,
+    project: &Project,
+    source: String,
+    files: Option<Vec<String>>,
+    delete: bool,
+    extra_args: &[String],
+) -> Result<SyncReport> {
     let mut cmd = Command::new("rsync");
-    cmd.arg("-rtv").arg("-e").arg(login_strings);
+    cmd.arg("-v");
+    if let Some(ref container) = host.container {
+        cmd.arg("-e").arg(container.rsh()?);
+    } else if !host.is_local() {
+        cmd.arg("-e").arg(ssh::login_command(host)?);
+        ssh::login_env(host, &mut cmd);
+    }
+    cmd.args(project.transfer.clone().unwrap_or_default().rsync_args());
+    cmd.arg(format!("--partial-dir={}", PARTIAL_DIR));
+    cmd.arg(format!("--out-format={}", OUT_FORMAT));
+    cmd.arg("--info=progress2").arg("--stats");
+    // the manifest is not in src, keep --delete from removing it
+    cmd.arg(format!("--filter=P /{}", manifest::MANIFEST_FILE));
     if delete {
         cmd.arg("--delete");
     }
-    match project.exclude {
-        None => {}
-        Some(ref exclude_files) => {
-            for file in exclude_files.iter() {
-                cmd.arg("--exclude").arg(file);
-            }
+    if let Some(ref files) = files {
+        info!("{}: sync {} files", project.name, files.len());
+        if delete {
+            warn!("--delete only removes files in the directories of the listed files");
         }
+        // the excludes still apply to the listed files
+        cmd.arg("--files-from=-").arg("--from0");
     }
-    let target = format!("{}@{}:{}", username, remote_ip, project.dest);
+    cmd.args(extra_args);
+    cmd.args(selection_args(project));
+    let target = match host.container {
+        Some(ref container) => format!("{}:{}", container.name, project.dest),
+        None if host.is_local() => project.dest.clone(),
+        None => format!("{}:{}", ssh::target(host), project.dest),
+    };
     cmd.arg(source).arg(target);
     debug!("{:?}", cmd);
-    let output = cmd.output()?;
-    if output.stdout.len() > 0 {
-        info!("rsync output:\n {}", String::from_utf8_lossy(&output.stdout));
+    // Ctrl-C in the terminal is for rn, which then decides whether to let
+    // the transfer finish
+    ignore_interrupt(&mut cmd);
+    if files.is_some() {
+        cmd.stdin(Stdio::piped());
     }
-    if output.stderr.len() > 0 {
-        error!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    // rsync reads the list while it writes to stdout, write it from a thread
+    let stdin = child.stdin.take();
+    let writer = spawn_scoped(move || -> io::Result<()> {
+        if let (Some(mut pipe), Some(files)) = (stdin, files) {
+            for file in files.iter() {
+                pipe.write_all(file.as_bytes())?;
+                pipe.write_all(b"\0")?;
+            }
+        }
+        Ok(())
+    });
+    let stdout = child.stdout.take();
+    let stdout = spawn_scoped(move || match stdout {
+        Some(pipe) => progress::read_stdout(pipe),
+        None => String::new(),
+    });
+    let stderr = read_in_background(child.stderr.take());
+    let status = wait_child(&mut child);
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if let Ok(Err(e)) = writer.join() {
+        warn!("write the file list to rsync failed: {}", e);
+    }
+    if stdout.len() > 0 {
+        if output::is_json() {
+            debug!("rsync output:\n {}", stdout);
+        } else {
+            info!("rsync output:\n {}", stdout);
+        }
+    }
+    if stderr.len() > 0 {
+        error!("stderr: {}", stderr);
+    }
+    let status = status?;
+
+    let mut report = SyncReport::default();
+    parse_output(&stdout, &mut report);
+    report.errors = stderr.lines().filter(|line| line.trim().len() > 0).map(String::from).collect();
+    if !status.success() {
+        report.errors.push(format!("rsync exited with {}", status));
     }
-    Ok(())
+    Ok(report)
+}
+
+/// spawn a thread logging to the logger of the calling thread, the scope of
+/// slog_scope does not follow it otherwise
+fn spawn_scoped<F, T>(f: F) -> thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let logger = slog_scope::logger();
+    thread::spawn(move || slog_scope::scope(&logger, f))
+}
+
+/// read all of a pipe of a child from a thread
+pub fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    spawn_scoped(move || {
+        let mut output = String::new();
+        if let Some(mut pipe) = pipe {
+            let mut buf = Vec::new();
+            let _ = pipe.read_to_end(&mut buf);
+            output = String::from_utf8_lossy(&buf).into_owned();
+        }
+        output
+    })
+}
+
+/// wait for rsync to exit; after a shutdown signal the transfer is given
+/// `SHUTDOWN_TIMEOUT` to complete before it is terminated
+fn wait_child(child: &mut Child) -> io::Result<ExitStatus> {
+    wait_or_cancel(child, &signal::shutdown_requested, &signal::force_requested, SHUTDOWN_TIMEOUT)
+}
+
+/// wait for a child ignoring SIGINT, it is cancelled
+/// `timeout` after shutdown returns true, or as soon as force does
+fn wait_or_cancel(
+    child: &mut Child,
+    shutdown: &dyn Fn() -> bool,
+    force: &dyn Fn() -> bool,
+    timeout: Duration,
+) -> io::Result<ExitStatus> {
+    let mut deadline: Option<Instant> = None;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if shutdown() {
+            let deadline = *deadline.get_or_insert_with(|| {
+                info!(
+                    "waiting up to {}s for the running transfer to finish, press Ctrl-C again to cancel it",
+                    timeout.as_secs()
+                );
+                Instant::now() + timeout
+            });
+            if force() || Instant::now() >= deadline {
+                warn!("cancel the running transfer, partial files are kept in {}", PARTIAL_DIR);
+                terminate(child)?;
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "transfer cancelled"));
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// stop the child, SIGTERM first, SIGKILL if it is still running after
+/// `KILL_TIMEOUT`; the ssh started by rsync exits with it
+fn terminate(child: &mut Child) -> io::Result<ExitStatus> {
+    let pid = child.id() as libc::pid_t;
+    unsafe {
+        libc::kill(pid, libc::SIGTERM);
+    }
+    let deadline = Instant::now() + KILL_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    warn!("the transfer did not stop, kill it");
+    unsafe {
+        libc::kill(pid, libc::SIGKILL);
+    }
+    child.wait()
 }
 
 #[cfg(test)]
@@ -73,43 +492,155 @@ mod tests {
     use super::*;
     use super::super::sshconfig::Host;
     use super::super::toml_parser::Project;
-    use shellexpand::tilde;
-    use std::path::{Path, PathBuf};
+    use super::super::transfer::TransferOptions;
+    use slog;
+    use std::sync::{Arc, Mutex};
 
-    #[test]
-    fn test_sync() {
-        let id = tilde("~/.ssh/id_rsa").into_owned();
-        let host = Host::new("ubuntu", "ubuntu", Some(PathBuf::from(id)), None, Some(2222));
-        let project = Project {
-            name: "test".to_string(),
-            src: "/tmp/a".to_string(),
-            dest: "/home/ubuntu/a".to_string(),
-            exclude: Some(vec![
-                "a.txt".to_string(),
-                "b.txt".to_string(),
-            ]),
-        };
+    /// keeps the messages logged
+    struct Collect(Arc<Mutex<Vec<String>>>);
 
-        if let Err(e) = sync(&host, &project, true) {
-            assert!(false, "rsync test password failed");
-        } else {
-            assert!(true);
+    impl slog::Drain for Collect {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> ::std::result::Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
         }
+    }
+
+    #[test]
+    fn test_spawn_scoped() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = slog::Logger::root(Collect(messages.clone()), o!());
+        slog_scope::scope(&logger, || spawn_scoped(|| info!("from the thread")).join().unwrap());
+        assert_eq!(*messages.lock().unwrap(), vec!["from the thread".to_string()]);
+    }
+
+    #[test]
+    fn test_unsupported_without_rsync() {
+        let mut project: Project = ::toml::from_str("name = \"web\"\nsrc = \"/tmp\"\ndest = \"/srv/web\"\n").unwrap();
+        // what the wan profile sets on every project
+        project.transfer = Some(TransferOptions {
+            compress: Some(true),
+            bwlimit: Some("1m".to_string()),
+            ..Default::default()
+        });
+        project.transfer.as_mut().unwrap().symlinks = Some(SymlinkPolicy::Preserve);
+        assert!(unsupported_without_rsync(&project, false).is_empty());
+        assert_eq!(unsupported_without_rsync(&project, true), vec!["--delete"]);
+        project.transfer.as_mut().unwrap().chmod = Some("F644".to_string());
+        project.gitignore = Some(true);
+        assert_eq!(unsupported_without_rsync(&project, false), vec!["transfer.chmod", "gitignore"]);
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let stdout = r##"sending incremental file list
+send 4096 a/
+send 12 a/b.txt
+send 0 c d.txt
+del. 0 old/
+del. 0 old.txt
+
+Number of files: 3 (reg: 2, dir: 1)
+Number of created files: 0
+Number of deleted files: 2 (reg: 1, dir: 1)
+Number of regular files transferred: 2
+Total file size: 12 bytes
+Total transferred file size: 12 bytes
+Literal data: 12 bytes
+Matched data: 0 bytes
+File list size: 0
+File list generation time: 0.001 seconds
+File list transfer time: 0.000 seconds
+Total bytes sent: 1,234
+Total bytes received: 35
+
+sent 1,234 bytes  received 35 bytes  2,538.00 bytes/sec
+total size is 12  speedup is 0.01
+"##;
+        let mut report = SyncReport::default();
+        parse_output(stdout, &mut report);
+        assert_eq!(report.files, vec!["a/b.txt".to_string(), "c d.txt".to_string()]);
+        assert_eq!(report.deleted, vec!["old".to_string(), "old.txt".to_string()]);
+        assert_eq!(report.bytes, 1234);
+        assert_eq!(
+            report.stats,
+            SyncStats {
+                files: 3,
+                files_transferred: 2,
+                total_size: 12,
+                transferred_size: 12,
+                bytes_sent: 1234,
+                bytes_received: 35,
+                speedup: 0.01,
+            }
+        );
+    }
 
-        let host = Host::new("ubuntu", "ubuntu", None::<PathBuf>, Some("ubuntu"), Some(2222));
+    #[test]
+    #[ignore = "needs rsync, run with cargo test -- --ignored"]
+    fn test_sync_local() {
+        let dir = Path::new("/tmp").join(format!("rn_sync_local_{}", std::process::id()));
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        for path in &["a.txt", "keep.txt", "sub/c.txt"] {
+            fs::write(src.join(path), path).unwrap();
+        }
+        fs::write(dest.join("old.txt"), "old").unwrap();
         let project = Project {
-            name: "test".to_string(),
-            src: "/tmp/b".to_string(),
-            dest: "/home/ubuntu/b".to_string(),
-            exclude: Some(vec![
-                "a.txt".to_string(),
-                "b.txt".to_string(),
-            ]),
+            name: "local".to_string(),
+            src: src.to_string_lossy().into_owned(),
+            dest: format!("{}/", dest.display()),
+            exclude: Some(vec!["a.txt".to_string()]),
+            ..Default::default()
         };
-        if let Err(e) = sync(&host, &project, true) {
-            assert!(false, "rsync test password failed");
-        } else {
-            assert!(true);
-        }
+        let report = sync(&Host::local(), &project, true).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.deleted, vec!["old.txt".to_string()]);
+        assert_eq!(fs::read_to_string(dest.join("sub/c.txt")).unwrap(), "sub/c.txt");
+        assert!(dest.join("keep.txt").is_file());
+        assert!(!dest.join("a.txt").exists());
+        assert!(!dest.join("old.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// a shell standing in for rsync, started like it
+    fn spawn_child(script: &str) -> Child {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        ignore_interrupt(&mut cmd);
+        cmd.spawn().unwrap()
+    }
+
+    /// whether the child is still running, an exited one may wait a while to
+    /// be reaped
+    fn is_running(child: &Child) -> bool {
+        // "pid (comm) state ...", comm may contain spaces
+        let stat = fs::read_to_string(format!("/proc/{}/stat", child.id())).unwrap_or_default();
+        stat.rsplit(')').next().unwrap_or("").split_whitespace().next().map_or(false, |state| state != "Z")
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut child = spawn_child("sleep 30");
+        let started = Instant::now();
+        let result = wait_or_cancel(&mut child, &|| true, &|| true, SHUTDOWN_TIMEOUT);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert!(started.elapsed() < KILL_TIMEOUT);
+        thread::sleep(Duration::from_millis(200));
+        assert!(!is_running(&child));
+
+        // the shell ignores SIGTERM, it is killed after KILL_TIMEOUT
+        let mut child = spawn_child("trap '' TERM; while :; do sleep 0.1; done");
+        thread::sleep(Duration::from_millis(200));
+        let started = Instant::now();
+        let result = wait_or_cancel(&mut child, &|| true, &|| false, Duration::from_millis(100));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert!(started.elapsed() >= KILL_TIMEOUT);
+        thread::sleep(Duration::from_millis(200));
+        assert!(!is_running(&child));
     }
 }
@@ -0,0 +1,69 @@
+extern crate libc;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static SHUTDOWN_SIGNAL: AtomicUsize = AtomicUsize::new(0);
+static SHUTDOWN_COUNT: AtomicUsize = AtomicUsize::new(0);
+static RELOAD_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn handle_signal(sig: libc::c_int) {
+    // only async-signal-safe work here: record the signal and return
+    if sig == libc::SIGHUP {
+        RELOAD_GENERATION.fetch_add(1, Ordering::SeqCst);
+    } else {
+        SHUTDOWN_SIGNAL.store(sig as usize, Ordering::SeqCst);
+        SHUTDOWN_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// install handlers for SIGINT/SIGTERM (shutdown) and SIGHUP (reload settings)
+pub fn install() {
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGHUP, handler);
+    }
+}
+
+/// ask the running watch loop and transfers to stop, as SIGTERM would
+pub fn request_shutdown() {
+    handle_signal(libc::SIGTERM);
+}
+
+/// true once SIGINT or SIGTERM has been received
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_COUNT.load(Ordering::SeqCst) > 0
+}
+
+/// true when the shutdown signal was sent more than once,
+/// in which case an in-flight transfer should not be waited for
+pub fn force_requested() -> bool {
+    SHUTDOWN_COUNT.load(Ordering::SeqCst) > 1
+}
+
+/// increased on every SIGHUP, each watcher reloads its settings when
+/// the value differs from the one it saw last
+pub fn reload_generation() -> usize {
+    RELOAD_GENERATION.load(Ordering::SeqCst)
+}
+
+/// the process exit status after a signal: 128 + signal number
+pub fn exit_code() -> Option<i32> {
+    match SHUTDOWN_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(128 + sig as i32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_generation() {
+        let generation = reload_generation();
+        handle_signal(libc::SIGHUP);
+        assert!(reload_generation() > generation);
+    }
+}
@@ -0,0 +1,317 @@
+extern crate libc;
+
+use errors::*;
+use sha2::{Digest, Sha256};
+use std::{fs, process, thread};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use super::sshconfig::{servername2ip, Host};
+use super::util::{ignore_interrupt, runtime_dir};
+
+/// how long to wait for a master connection to be up
+const MASTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// quote a string for the remote shell
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", r"'\''"))
+}
+
+/// the ssh command line logging in the host, one argument each; the
+/// password is given to sshpass in the environment by `login_env`
+pub fn login_args(host: &Host) -> io::Result<Vec<String>> {
+    let mut args: Vec<String> = match (&host.identityfile, &host.password) {
+        (&Some(ref path), _) => vec![
+            "ssh".to_string(),
+            "-i".to_string(),
+            path.to_string_lossy().into_owned(),
+            "-p".to_string(),
+            host.port.to_string(),
+            "-o".to_string(),
+            "UserKnownHostsFile=/dev/null".to_string(),
+            "-o".to_string(),
+            "StrictHostKeyChecking no".to_string(),
+        ],
+        (&None, &Some(_)) => vec![
+            "sshpass".to_string(),
+            "-e".to_string(),
+            "ssh".to_string(),
+            "-l".to_string(),
+            host.user.clone(),
+            "-p".to_string(),
+            host.port.to_string(),
+        ],
+        (&None, &None) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no password or identifile was set!"));
+        }
+    };
+    args.push("-o".to_string());
+    args.push("ConnectTimeout=2".to_string());
+    if let Some(ref path) = host.control_path {
+        // without a working master, ssh logs in by itself
+        args.push("-o".to_string());
+        args.push("ControlMaster=no".to_string());
+        args.push("-o".to_string());
+        args.push(format!("ControlPath={}", path.display()));
+    }
+    Ok(args)
+}
+
+/// give the password of the host to the sshpass of `login_args` run by cmd
+pub fn login_env(host: &Host, cmd: &mut Command) {
+    if let (&None, &Some(ref password)) = (&host.identityfile, &host.password) {
+        cmd.env("SSHPASS", password);
+    }
+}
+
+/// quote an argument both for sh and the splitting of `rsync -e`, which
+/// has no backslash escapes
+fn quote_rsh(s: &str) -> String {
+    format!("'{}'", s.replace("'", r#"'"'"'"#))
+}
+
+/// the ssh command used to login the host, as given to `rsync -e`, with
+/// `login_env` set on rsync
+pub fn login_command(host: &Host) -> io::Result<String> {
+    let args: Vec<String> = login_args(host)?.iter().map(|arg| quote_rsh(arg)).collect();
+    Ok(args.join(" "))
+}
+
+/// ssh logging in the host, followed by the arguments
+fn login(host: &Host) -> io::Result<Command> {
+    let args = login_args(host)?;
+    let mut cmd = Command::new(&args[0]);
+    cmd.args(&args[1..]);
+    login_env(host, &mut cmd);
+    Ok(cmd)
+}
+
+/// user@ip of the host
+pub fn target(host: &Host) -> String {
+    if !host.is_ssh() {
+        return host.hostname.clone();
+    }
+    format!("{}@{}", host.user, servername2ip(host.hostname.as_str()))
+}
+
+/// a shell running the command on the host, through ssh unless the host is
+/// local or a container
+pub fn command(host: &Host, remote_command: &str) -> Result<Command> {
+    if let Some(ref container) = host.container {
+        return Ok(container.command(remote_command));
+    }
+    if host.is_local() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(remote_command);
+        return Ok(cmd);
+    }
+    // ssh gives the command to the shell of the remote user
+    let mut cmd = login(host)?;
+    cmd.arg(target(host)).arg(remote_command);
+    Ok(cmd)
+}
+
+/// run a shell command on the host and return its stdout
+pub fn exec(host: &Host, remote_command: &str, input: Option<&[u8]>) -> Result<String> {
+    let target = target(host);
+    let mut cmd = command(host, remote_command)?;
+    cmd.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    debug!("run on {}: {}", target, remote_command);
+    let mut child = cmd.spawn()?;
+    if let Some(input) = input {
+        // stdin is closed when dropped, which ends the input of the remote command
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input)?;
+        }
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "run {:?} on {} failed: {}",
+            remote_command,
+            target,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// a persistent ssh connection to a host, the ssh and rsync commands run
+/// with `Master::host` log in through it instead of doing a handshake each
+pub struct Master {
+    host: Host,
+    socket: PathBuf,
+    child: Option<Child>,
+}
+
+impl Master {
+    /// connect to the host
+    pub fn new(host: &Host) -> Result<Master> {
+        let key = format!("{}@{}:{}", host.user, host.hostname, host.port);
+        let hash: String = Sha256::digest(key.as_bytes()).iter().take(8).map(|b| format!("{:02x}", b)).collect();
+        let mut master = Master {
+            host: Host {
+                control_path: None,
+                ..host.clone()
+            },
+            // short, a unix socket path is limited to about 100 bytes
+            socket: runtime_dir()?.join(format!("ssh-{}-{}", process::id(), hash)),
+            child: None,
+        };
+        master.connect()?;
+        Ok(master)
+    }
+
+    /// the host, logging in through the master connection
+    pub fn host(&self) -> Host {
+        Host {
+            control_path: Some(self.socket.clone()),
+            ..self.host.clone()
+        }
+    }
+
+    /// whether this is a connection to host with the same login
+    pub fn is_for(&self, host: &Host) -> bool {
+        self.host == Host {
+            control_path: None,
+            ..host.clone()
+        }
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.close();
+        let mut cmd = login(&self.host)?;
+        cmd.arg("-o")
+            .arg("ControlMaster=yes")
+            .arg("-o")
+            .arg(format!("ControlPath={}", self.socket.display()))
+            .arg("-o")
+            .arg("ServerAliveInterval=15")
+            .arg("-N")
+            .arg(target(&self.host))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        // keep the connection up for the running rsync after Ctrl-C
+        ignore_interrupt(&mut cmd);
+        self.child = Some(cmd.spawn()?);
+        let started = Instant::now();
+        while started.elapsed() < MASTER_TIMEOUT {
+            if self.is_alive() {
+                info!("ssh master connection to {} is up", target(&self.host));
+                return Ok(());
+            }
+            if let Some(Ok(Some(status))) = self.child.as_mut().map(|child| child.try_wait()) {
+                self.child = None;
+                bail!("ssh master connection to {} failed: {}", target(&self.host), status);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        self.close();
+        bail!("ssh master connection to {} timed out", target(&self.host))
+    }
+
+    /// ask the master whether it is still running
+    fn control(&self, operation: &str) -> bool {
+        Command::new("ssh")
+            .arg("-o")
+            .arg(format!("ControlPath={}", self.socket.display()))
+            .arg("-O")
+            .arg(operation)
+            .arg(target(&self.host))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false)
+    }
+
+    pub fn is_alive(&mut self) -> bool {
+        match self.child.as_mut().map(|child| child.try_wait()) {
+            Some(Ok(None)) => self.control("check"),
+            _ => false,
+        }
+    }
+
+    /// reconnect if the connection is down, e.g. after the network changed
+    pub fn ensure(&mut self) -> Result<()> {
+        if self.is_alive() {
+            return Ok(());
+        }
+        warn!("ssh master connection to {} is down, reconnect", target(&self.host));
+        self.connect()
+    }
+
+    fn close(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            if !self.control("exit") {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+        }
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/home/ubuntu/a b"), "'/home/ubuntu/a b'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn test_login_command() {
+        let mut host = Host::new("10.10.20.20", "root", Some("/tmp/id_rsa"), None, None);
+        assert!(!login_command(&host).unwrap().contains("ControlPath"));
+        host.control_path = Some(PathBuf::from("/tmp/rn-0/ssh-1"));
+        let login = login_command(&host).unwrap();
+        assert!(login.ends_with(" '-o' 'ControlMaster=no' '-o' 'ControlPath=/tmp/rn-0/ssh-1'"));
+    }
+
+    #[test]
+    fn test_login_quoting() {
+        let host = Host::new("10.10.20.20", "root", Some("/tmp/it's a $key; rm -rf x"), None, None);
+        // sh splits the command back into the arguments
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("for arg in {}; do printf '%s\\n' \"$arg\"; done", login_command(&host).unwrap()))
+            .output()
+            .unwrap();
+        let args: Vec<String> = String::from_utf8(output.stdout).unwrap().lines().map(String::from).collect();
+        assert_eq!(args, login_args(&host).unwrap());
+        assert_eq!(args[2], "/tmp/it's a $key; rm -rf x");
+
+        let host = Host::new("10.10.20.20", "root", None::<&str>, Some("p a'ss; $(id)"), None);
+        let args = login_args(&host).unwrap();
+        assert_eq!(&args[..3], &["sshpass", "-e", "ssh"]);
+        assert!(!login_command(&host).unwrap().contains("p a"));
+        let cmd = command(&host, "true").unwrap();
+        assert_eq!(cmd.get_program(), "sshpass");
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert_eq!(envs, vec![(OsStr::new("SSHPASS"), Some(OsStr::new("p a'ss; $(id)")))]);
+    }
+
+    #[test]
+    fn test_master_unreachable() {
+        // nothing listens on port 1
+        let host = Host::new("127.0.0.1", "root", Some("/tmp/id_rsa"), None, Some(1));
+        let started = Instant::now();
+        assert!(Master::new(&host).is_err());
+        assert!(started.elapsed() < MASTER_TIMEOUT);
+    }
+}
@@ -6,12 +6,36 @@ use std::io::BufReader;
 use std::fs::File;
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::env;
 use std::fmt::Debug;
 use shellexpand::tilde;
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::net::{IpAddr, ToSocketAddrs, SocketAddr};
+use super::container::Container;
 
 
 
+/// the server name of a local destination
+pub const LOCAL_SERVER: &str = "local";
+
+/// how the files get to a host
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostKind {
+    /// rsync over ssh
+    Ssh,
+    /// a directory of this machine, e.g. a bind mount or an NFS share
+    Local,
+    /// a docker or podman container, or a kubernetes pod, see `Host::container`
+    #[serde(skip_deserializing)]
+    Container,
+}
+
+impl Default for HostKind {
+    fn default() -> Self {
+        HostKind::Ssh
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Host {
     pub hostname: String,
@@ -19,6 +43,11 @@ pub struct Host {
     pub user: String,
     pub password: Option<String>,
     pub port: u16,
+    /// the socket of a master connection to log in through, see `ssh::Master`
+    pub control_path: Option<PathBuf>,
+    pub kind: HostKind,
+    /// how to run commands in the container of a `HostKind::Container`
+    pub container: Option<Container>,
 }
 
 
@@ -46,8 +75,72 @@ impl Host {
                 Some(ref pwd) => Some(pwd.as_ref().to_string()),
             },
             port: port.unwrap_or(22),
+            control_path: None,
+            kind: HostKind::Ssh,
+            container: None,
         }
     }
+
+    /// this machine, dest is a local path
+    pub fn local() -> Self {
+        Host {
+            kind: HostKind::Local,
+            ..Host::new("localhost", env::var("USER").unwrap_or_default().as_str(), None::<PathBuf>, None, None)
+        }
+    }
+
+    /// a container given as e.g. `docker://web/srv/app`, named after it
+    pub fn container(target: &str, container: Container) -> Self {
+        Host {
+            kind: HostKind::Container,
+            container: Some(container),
+            ..Host::new(target, "", None::<PathBuf>, None, None)
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.kind == HostKind::Local
+    }
+
+    /// whether the host is logged in with ssh
+    pub fn is_ssh(&self) -> bool {
+        self.kind == HostKind::Ssh
+    }
+}
+
+/// whether the server given on the command line is this machine: `local` or `local:`
+pub fn is_local_server(server: &str) -> bool {
+    server.trim_end_matches(':') == LOCAL_SERVER
+}
+
+/// where the hosts named in `~/.ssh/config` come from
+pub trait SshConfigSource {
+    /// the hosts by name
+    fn hosts(&self) -> Result<HashMap<String, Host>>;
+}
+
+/// an ssh config file
+#[derive(Debug, Clone)]
+pub struct SshConfigFile(pub PathBuf);
+
+impl SshConfigFile {
+    /// `~/.ssh/config`
+    pub fn user() -> Self {
+        SshConfigFile(PathBuf::from(tilde("~/.ssh/config").into_owned()))
+    }
+}
+
+impl SshConfigSource for SshConfigFile {
+    fn hosts(&self) -> Result<HashMap<String, Host>> {
+        parse_ssh_config(&self.0)
+    }
+}
+
+/// hosts given by the caller, e.g. when rn is embedded in another tool
+impl SshConfigSource for HashMap<String, Host> {
+    fn hosts(&self) -> Result<HashMap<String, Host>> {
+        Ok(self.clone())
+    }
 }
 
 /// 解析ssh config文件
@@ -154,6 +247,25 @@ pub fn servername2ip(servername: &str) -> String {
     ip
 }
 
+/// whether the host is in a private network: a private, loopback or link
+/// local address, or a name resolving to one
+pub fn is_lan(hostname: &str) -> bool {
+    let ip = match hostname.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => match (hostname, 22).to_socket_addrs().ok().and_then(|mut a| a.next()) {
+            Some(address) => address.ip(),
+            None => return false,
+        },
+    };
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        // fc00::/7 are the unique local addresses, fe80::/10 the link local ones
+        IpAddr::V6(ip) => {
+            ip.is_loopback() || ip.segments()[0] & 0xfe00 == 0xfc00 || ip.segments()[0] & 0xffc0 == 0xfe80
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -189,6 +301,9 @@ Host ubuntu
                 user: "pi".to_string(),
                 password: None,
                 port: 2222,
+                control_path: None,
+                kind: HostKind::Ssh,
+                container: None,
             },
         );
         result.insert(
@@ -199,6 +314,9 @@ Host ubuntu
                 user: "ubuntu".to_string(),
                 password: None,
                 port: 22,
+                control_path: None,
+                kind: HostKind::Ssh,
+                container: None,
             },
         );
         assert_eq!(result, v);
@@ -218,12 +336,21 @@ Host ubuntu
 
     #[test]
     fn test_servername2ip() {
-        let servername = "ubuntu";
-        let ip = servername2ip(servername);
-        assert_eq!("192.168.75.129".to_string(), ip);
+        let ip = servername2ip("localhost");
+        assert!(ip == "127.0.0.1" || ip == "::1", "{}", ip);
 
         let servername = "192.168.1.1";
         let ip = servername2ip(servername);
         assert_eq!(servername.to_string(), ip);
     }
+
+    #[test]
+    fn test_is_lan() {
+        assert!(is_lan("192.168.1.11"));
+        assert!(is_lan("10.10.20.20"));
+        assert!(is_lan("127.0.0.1"));
+        assert!(is_lan("fd12::1"));
+        assert!(!is_lan("8.8.8.8"));
+        assert!(!is_lan("2001:4860::8888"));
+    }
 }
@@ -1,37 +1,472 @@
-use toml;
 use errors::*;
 use std::fmt::Debug;
-use std::path::Path;
-use utils::util::load_file;
+use std::path::{Path, PathBuf};
+use utils::config::{self, find_project_file, Layer};
+use utils::git;
+use utils::sshconfig::{is_lan, HostKind};
+use utils::transfer::{check_rsync_args, TransferOptions};
+use utils::util::create_re;
+use utils::validate;
 use std::cmp::PartialEq;
+use std::collections::BTreeMap;
+use toml::Value;
 use shellexpand::tilde;
-use std::env::current_dir;
+use std::env::{self, current_dir};
 
 
 
 #[derive(Debug, Deserialize)]
 pub struct GlobalConfig {
-    pub global_user: String,
+    /// needed only for the servers which are not in ~/.ssh/config
+    pub global_user: Option<String>,
     pub global_password: Option<String>,
     pub global_key: Option<String>,
     pub global_port: Option<u16>,
-    pub global_dest_root: String,
+    /// needed only for the `.` project
+    pub global_dest_root: Option<String>,
+    /// applied to every project unless it sets `global_exclude = false`
     pub global_exclude: Option<Vec<String>>,
+    /// passed to rsync before the rsync_args of each project
+    pub global_rsync_args: Option<Vec<String>>,
+    /// the filter_file of the projects which do not set one
+    pub global_filter_file: Option<String>,
+    /// named lists of excludes, used by the projects with `exclude_sets`
+    pub exclude_sets: Option<BTreeMap<String, Vec<String>>>,
+    /// the link settings of each server, by the name given on the command line or its address
+    pub hosts: Option<BTreeMap<String, HostSettings>>,
+    /// named link settings, `lan` and `wan` are built in
+    pub profiles: Option<BTreeMap<String, ProfileSettings>>,
     pub projects: Option<Vec<Project>>,
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq)]
+/// the keys of GlobalConfig, anything else in a config file is reported
+pub const GLOBAL_KEYS: &[&str] = &[
+    "global_user",
+    "global_password",
+    "global_key",
+    "global_port",
+    "global_dest_root",
+    "global_exclude",
+    "global_rsync_args",
+    "global_filter_file",
+    "exclude_sets",
+    "hosts",
+    "profiles",
+    "projects",
+];
+
+/// the keys of HostSettings, ProfileSettings has them but `kind` and `profile`
+pub const HOST_KEYS: &[&str] = &["kind", "profile", "bandwidth_limit", "compress"];
+
+/// the profile of the hosts in the private networks
+pub const LAN_PROFILE: &str = "lan";
+/// the profile of the other hosts
+pub const WAN_PROFILE: &str = "wan";
+
+/// how the files are sent to a host
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct ProfileSettings {
+    /// in KiB/s, or with a suffix, e.g. "1.5m"
+    pub bandwidth_limit: Option<String>,
+    pub compress: Option<bool>,
+}
+
+impl ProfileSettings {
+    /// the settings of over, and the ones of self which over does not set
+    fn merge(&self, over: &ProfileSettings) -> ProfileSettings {
+        ProfileSettings {
+            bandwidth_limit: over.bandwidth_limit.clone().or_else(|| self.bandwidth_limit.clone()),
+            compress: over.compress.or(self.compress),
+        }
+    }
+
+    fn transfer(&self) -> TransferOptions {
+        TransferOptions {
+            bwlimit: self.bandwidth_limit.clone(),
+            compress: self.compress,
+            ..Default::default()
+        }
+    }
+}
+
+/// the settings of a host in `[hosts.<server>]`
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct HostSettings {
+    /// `local` for a directory of this machine, `ssh` by default
+    pub kind: Option<HostKind>,
+    /// chosen from the address of the host if not set: lan for the private
+    /// and loopback addresses, wan for the others
+    pub profile: Option<String>,
+    /// override the profile
+    pub bandwidth_limit: Option<String>,
+    pub compress: Option<bool>,
+}
+
+/// the keys of Project
+pub const PROJECT_KEYS: &[&str] = &[
+    "name",
+    "src",
+    "dest",
+    "exclude",
+    "watch",
+    "hosts",
+    "manifest",
+    "deploy_mode",
+    "keep_releases",
+    "extends",
+    "exclude_sets",
+    "global_exclude",
+    "gitignore",
+    "source",
+    "transfer",
+    "rsync_args",
+    "filter_file",
+    "agent",
+];
+
+/// how a project is synced to dest
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeployMode {
+    /// sync into dest
+    Direct,
+    /// sync into dest/releases/<timestamp> and point dest/current to it
+    Release,
+}
+
+/// which files of src are synced
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceMode {
+    /// all the files which are not excluded
+    All,
+    /// only the files tracked by git
+    GitTracked,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
 pub struct Project {
     pub name: String,
     pub src: String,
     pub dest: String,
     pub exclude: Option<Vec<String>>,
+    /// watched by `rn daemon`
+    pub watch: Option<bool>,
+    /// the servers `rn daemon` syncs the project to
+    pub hosts: Option<Vec<String>>,
+    /// write a manifest of the synced files to dest, used by `rn verify`
+    pub manifest: Option<bool>,
+    pub deploy_mode: Option<DeployMode>,
+    /// how many releases are kept in release mode, 5 by default
+    pub keep_releases: Option<usize>,
+    /// the project whose settings are inherited
+    pub extends: Option<String>,
+    /// the names of the exclude sets added to exclude
+    pub exclude_sets: Option<Vec<String>>,
+    /// false to not apply global_exclude
+    pub global_exclude: Option<bool>,
+    /// exclude the files ignored by the .gitignore files in src
+    pub gitignore: Option<bool>,
+    /// which files of src are synced
+    pub source: Option<SourceMode>,
+    /// how the files are transferred
+    pub transfer: Option<TransferOptions>,
+    /// extra rsync arguments, e.g. ["--inplace", "--fuzzy"]
+    pub rsync_args: Option<Vec<String>>,
+    /// a file of rsync filter rules, relative to src
+    pub filter_file: Option<String>,
+    /// push the changes through an rn agent on the host while watching
+    pub agent: Option<bool>,
+    /// sync only the files changed since this git ref, not read from the
+    /// config files but set from `SyncOptions`, i.e. --since
+    #[serde(skip)]
+    pub since: Option<String>,
 }
 
+impl GlobalConfig {
+    /// the project with the global settings applied: the excludes, the
+    /// rsync_args and the filter_file, resolved relative to src
+    pub fn apply_globals(&self, project: Project) -> Result<Project> {
+        let exclude = self.excludes_of(&project)?;
+        let mut rsync_args = self.global_rsync_args.clone().unwrap_or_default();
+        rsync_args.extend(project.rsync_args.iter().flat_map(|args| args.iter().cloned()));
+        let filter_file = project.filter_file.clone().or_else(|| self.global_filter_file.clone()).map(|file| {
+            let file = tilde(&file).into_owned();
+            if Path::new(&file).is_relative() {
+                Path::new(&project.src).join(file).to_string_lossy().into_owned()
+            } else {
+                file
+            }
+        });
+        Ok(Project {
+            exclude,
+            rsync_args: if rsync_args.is_empty() { None } else { Some(rsync_args) },
+            filter_file,
+            ..project
+        })
+    }
+
+    /// the settings of a profile, the ones in [profiles] override the built-in lan and wan
+    pub fn profile(&self, name: &str) -> Result<ProfileSettings> {
+        let builtin = match name {
+            LAN_PROFILE => Some(ProfileSettings {
+                bandwidth_limit: None,
+                compress: Some(false),
+            }),
+            WAN_PROFILE => Some(ProfileSettings {
+                bandwidth_limit: None,
+                compress: Some(true),
+            }),
+            _ => None,
+        };
+        match (builtin, self.profiles.as_ref().and_then(|profiles| profiles.get(name))) {
+            (builtin, Some(profile)) => Ok(builtin.unwrap_or_default().merge(profile)),
+            (Some(builtin), None) => Ok(builtin),
+            (None, None) => bail!("unknown profile {:?}, it is not lan, wan or in [profiles]", name),
+        }
+    }
+
+    /// the settings of the server, given by name or by address
+    pub fn host_settings(&self, server: &str, address: &str) -> Option<&HostSettings> {
+        let hosts = self.hosts.as_ref()?;
+        hosts.get(server).or_else(|| hosts.get(address))
+    }
+
+    /// the project with the link settings of the host applied: those of its
+    /// profile, overridden by the transfer options of the project, overridden
+    /// by the settings of the host. Returns the name of the profile too
+    pub fn apply_host(&self, project: &mut Project, server: &str, address: &str) -> Result<String> {
+        let settings = self.host_settings(server, address).cloned().unwrap_or_default();
+        let name = match settings.profile {
+            Some(ref name) => name.clone(),
+            None if is_lan(address) => LAN_PROFILE.to_string(),
+            None => WAN_PROFILE.to_string(),
+        };
+        let host = ProfileSettings {
+            bandwidth_limit: settings.bandwidth_limit.clone(),
+            compress: settings.compress,
+        };
+        let transfer = self.profile(&name)?
+            .transfer()
+            .merge(&project.transfer.clone().unwrap_or_default())
+            .merge(&host.transfer());
+        project.transfer = Some(transfer);
+        Ok(name)
+    }
+
+    /// the excludes of a project: global_exclude unless it opts out, the
+    /// patterns of its exclude sets and its own
+    pub fn excludes_of(&self, project: &Project) -> Result<Option<Vec<String>>> {
+        let mut excludes: Vec<String> = Vec::new();
+        if project.global_exclude != Some(false) {
+            excludes.extend(self.global_exclude.iter().flat_map(|e| e.iter().cloned()));
+        }
+        for name in project.exclude_sets.iter().flat_map(|sets| sets.iter()) {
+            match self.exclude_sets.as_ref().and_then(|sets| sets.get(name)) {
+                Some(set) => excludes.extend(set.iter().cloned()),
+                None => bail!("project {}: unknown exclude set {:?}", project.name, name),
+            }
+        }
+        excludes.extend(project.exclude.iter().flat_map(|e| e.iter().cloned()));
+        let mut seen = Vec::new();
+        excludes.retain(|pattern| if seen.contains(pattern) {
+            false
+        } else {
+            seen.push(pattern.clone());
+            true
+        });
+        Ok(if excludes.is_empty() { None } else { Some(excludes) })
+    }
+}
+
+fn project_named<'a>(projects: &'a [Value], name: &str) -> Option<&'a Value> {
+    projects
+        .iter()
+        .find(|project| project.get("name").and_then(|n| n.as_str()) == Some(name))
+}
+
+/// the settings of a project merged over the ones of the projects it extends
+fn inherit(project: &Value, projects: &[Value], chain: &mut Vec<String>) -> Result<Value> {
+    let name = project.get("name").and_then(|n| n.as_str()).unwrap_or("").to_string();
+    let parent_name = match project.get("extends").and_then(|e| e.as_str()) {
+        Some(parent_name) => parent_name,
+        None => return Ok(project.clone()),
+    };
+    if chain.contains(&name) {
+        bail!("projects extend each other: {} -> {}", chain.join(" -> "), name);
+    }
+    chain.push(name.clone());
+    let parent = match project_named(projects, parent_name) {
+        Some(parent) => inherit(parent, projects, chain)?,
+        None => bail!("project {} extends unknown project {:?}", name, parent_name),
+    };
+    let mut merged = parent.as_table().cloned().unwrap_or_default();
+    for (key, value) in project.as_table().into_iter().flat_map(|t| t.iter()) {
+        match (key.as_str(), merged.get(key).and_then(|v| v.as_array()).cloned(), value.as_array()) {
+            // the lists of excludes add up
+            ("exclude", Some(mut inherited), Some(own)) | ("exclude_sets", Some(mut inherited), Some(own)) => {
+                inherited.extend(own.iter().filter(|v| !inherited.contains(v)).cloned().collect::<Vec<_>>());
+                merged.insert(key.clone(), Value::Array(inherited));
+            }
+            // the tables, e.g. transfer, are merged key by key
+            (_, _, None) if value.is_table() && merged.get(key).map_or(false, |v| v.is_table()) => {
+                let mut table = merged[key].as_table().cloned().unwrap_or_default();
+                for (k, v) in value.as_table().into_iter().flat_map(|t| t.iter()) {
+                    table.insert(k.clone(), v.clone());
+                }
+                merged.insert(key.clone(), Value::Table(table));
+            }
+            _ => {
+                merged.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Ok(Value::Table(merged))
+}
+
+/// merge the settings of the parents into the projects with `extends`
+pub fn resolve_extends(config: &mut Value) -> Result<()> {
+    let projects = match config.get("projects").and_then(|p| p.as_array()) {
+        Some(projects) => projects.clone(),
+        None => return Ok(()),
+    };
+    let mut resolved = Vec::new();
+    for project in projects.iter() {
+        resolved.push(inherit(project, &projects, &mut Vec::new())?);
+    }
+    if let Some(table) = config.as_table_mut() {
+        table.insert("projects".to_string(), Value::Array(resolved));
+    }
+    Ok(())
+}
+
+/// the values of the ${...} placeholders, other names are looked up in the env
+#[derive(Debug, Clone, Default)]
+pub struct TemplateContext {
+    pub project: String,
+    /// the server as given on the command line
+    pub host: String,
+    /// the user to login the server
+    pub user: String,
+    /// the branch checked out in src
+    pub git_branch: Option<String>,
+}
+
+impl TemplateContext {
+    fn get(&self, name: &str) -> Result<String> {
+        match name {
+            "project" => Ok(self.project.clone()),
+            "host" => Ok(self.host.clone()),
+            "user" => Ok(self.user.clone()),
+            "git_branch" => match self.git_branch {
+                Some(ref branch) => Ok(branch.clone()),
+                None => bail!("${{git_branch}} is used but there is no branch checked out in src"),
+            },
+            _ => env::var(name).chain_err(|| format!("undefined variable ${{{}}}", name)),
+        }
+    }
+}
+
+/// replace the ${name} placeholders in template, `$$` is a literal `$`
+pub fn expand(template: &str, context: &TemplateContext) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if after.starts_with('$') {
+            expanded.push('$');
+            rest = &after[1..];
+        } else if after.starts_with('{') {
+            let end = match after.find('}') {
+                Some(end) => end,
+                None => bail!("unclosed ${{ in {:?}", template),
+            };
+            expanded.push_str(&context.get(after[1..end].trim())?);
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = after;
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+impl Project {
+    /// resolve the placeholders in src, dest, exclude and filter_file
+    pub fn expand(&mut self, context: &TemplateContext) -> Result<()> {
+        if self.src.contains("${git_branch}") {
+            bail!("project {}: ${{git_branch}} can not be used in src", self.name);
+        }
+        self.src = tilde(&expand(&self.src, context)?).into_owned();
+        let mut context = context.clone();
+        context.git_branch = git::branch(&self.src);
+        self.dest = expand(&self.dest, &context)?;
+        if let Some(ref mut exclude) = self.exclude {
+            for pattern in exclude.iter_mut() {
+                *pattern = expand(pattern, &context)?;
+            }
+        }
+        if let Some(ref mut filter_file) = self.filter_file {
+            *filter_file = tilde(&expand(filter_file, &context)?).into_owned();
+        }
+        Ok(())
+    }
+
+    /// check if the project settings can be used to sync
+    pub fn validate(&self) -> Result<()> {
+        if !Path::new(&self.src).exists() {
+            bail!("project {}: src {:?} does not exist", self.name, self.src);
+        }
+        if self.dest.trim().is_empty() {
+            bail!("project {}: dest is empty", self.name);
+        }
+        if self.keep_releases == Some(0) {
+            bail!("project {}: keep_releases must be at least 1", self.name);
+        }
+        if let Some(ref args) = self.rsync_args {
+            check_rsync_args(args).chain_err(|| format!("project {}: invalid rsync_args", self.name))?;
+        }
+        if let Some(ref filter_file) = self.filter_file {
+            if !Path::new(filter_file).is_file() {
+                bail!("project {}: filter_file {:?} does not exist", self.name, filter_file);
+            }
+        }
+        if let Some(ref transfer) = self.transfer {
+            transfer.validate().chain_err(|| format!("project {}: invalid transfer", self.name))?;
+        }
+        if let Some(ref exclude) = self.exclude {
+            for pattern in exclude.iter() {
+                if create_re(pattern).is_none() {
+                    bail!("project {}: invalid exclude pattern {:?}", self.name, pattern);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the config merged from the system and user config, toml_path, the
+/// .rn.toml of the current directory and the RN_* env vars
 pub fn get_config(toml_path: &Path) -> Result<GlobalConfig> {
-    // get the project settings config from *.toml file
-    let toml_string = load_file(toml_path)?;
-    let mut g_config: GlobalConfig = toml::from_str(toml_string.as_str())?;
+    let vars: Vec<(String, String)> = env::vars().collect();
+    get_config_from(config::layer_files(toml_path), &vars)
+}
+
+/// the config merged from the given files and env vars
+pub fn get_config_from(files: Vec<(Layer, PathBuf)>, vars: &[(String, String)]) -> Result<GlobalConfig> {
+    let validation = validate::check_files(files, vars);
+    for diagnostic in validation.warnings() {
+        warn!("{}", diagnostic);
+    }
+    let mut g_config = match validation.config {
+        Some(config) if !validation.has_errors() => config,
+        _ => {
+            let errors: Vec<String> = validation.errors().map(|d| d.to_string()).collect();
+            bail!("invalid config:\n{}", errors.join("\n"));
+        }
+    };
     // change ~ into $HOME in the key
     g_config.global_key = match g_config.global_key {
         None => None,
@@ -41,20 +476,60 @@ pub fn get_config(toml_path: &Path) -> Result<GlobalConfig> {
     Ok(g_config)
 }
 
+/// the root of the project in dir: the root of its git repo, or the
+/// directory of its .rn.toml, or dir itself
+fn project_root(dir: &Path) -> PathBuf {
+    if let Some(root) = git::toplevel(dir) {
+        return root;
+    }
+    match find_project_file(dir).as_ref().and_then(|file| file.parent()) {
+        Some(root) => root.to_path_buf(),
+        None => dir.to_path_buf(),
+    }
+}
+
 fn get_project_current_path(config: &GlobalConfig) -> Result<Project> {
     let current_path = current_dir()?;
-    let dir_name = current_path.file_name().unwrap();
-    let dir_name = dir_name.to_string_lossy();
-    let dest_dir = Path::new(&config.global_dest_root).join(dir_name.to_string());
-    let src: String = current_path.to_str().unwrap().to_string();
-    let dest: String = dest_dir.to_str().unwrap().to_string();
+    let root = project_root(&current_path);
+    let in_git = git::toplevel(&root).is_some();
+    let dir_name = match git::repo_name(&root).or_else(|| root.file_name().map(|n| n.to_string_lossy().into_owned())) {
+        Some(name) => name,
+        None => bail!("can not sync {:?} as the current project, run rn in a project directory", root),
+    };
+    let dest_root = match config.global_dest_root {
+        Some(ref dest_root) => dest_root,
+        None => bail!("set global_dest_root in the config to sync the current directory"),
+    };
+    let dest_dir = Path::new(dest_root).join(&dir_name);
     let project = Project {
         name: ".".to_string(),
-        src,
-        dest,
-        exclude: config.global_exclude.clone(),
+        src: format!("{}/", root.to_string_lossy()),
+        dest: dest_dir.to_string_lossy().into_owned(),
+        gitignore: if in_git { Some(true) } else { None },
+        ..Default::default()
     };
-    Ok(project)
+    config.apply_globals(project)
+}
+
+/// the project whose src contains dir, the innermost one if they are nested
+pub fn project_for_dir<'a>(dir: &Path, config: &'a GlobalConfig) -> Option<&'a Project> {
+    let dir = dir.canonicalize().ok()?;
+    config
+        .projects
+        .iter()
+        .flat_map(|projects| projects.iter())
+        // the placeholders can not be resolved without the server
+        .filter(|project| !project.src.contains("${"))
+        .filter_map(|project| {
+            let src = Path::new(tilde(&project.src).as_ref()).canonicalize().ok()?;
+            if src.is_dir() && dir.starts_with(&src) {
+                Some((src.components().count(), project))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|&(depth, _)| depth)
+        .map(|(_, project)| project)
 }
 
 pub fn get_project_info<S>(project_name: S, config: &GlobalConfig) -> Result<Project>
@@ -68,7 +543,7 @@ where
             if project.name == project_name.as_ref() {
                 let mut info = project.clone();
                 info.src = tilde(&info.src).into_owned();
-                return Ok(info);
+                return config.apply_globals(info);
             }
         }
     }
@@ -80,12 +555,12 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::prelude::*;
 
     #[test]
     fn test_get_config() {
-        let tmp_path = Path::new("/tmp/a.toml");
+        let tmp_path = Path::new("/tmp").join(format!("rn_get_config_{}.toml", ::std::process::id()));
         let mut tmp_file = File::create(&tmp_path).unwrap();
         let content = r##"global_user = "root"
 global_key = "~/.ssh/id_rsa"
@@ -98,7 +573,9 @@ dest = "~/qdata-cloud/"
 exclude = [".git", "prometheus.yaml"]
 "##;
         tmp_file.write_all(content.as_bytes()).unwrap();
-        let global_config = get_config(tmp_path).unwrap();
+        // only this file, not the config of the user running the tests
+        let global_config = get_config_from(vec![(Layer::File, tmp_path.clone())], &[]).unwrap();
+        fs::remove_file(&tmp_path).unwrap();
 
         let project_name = "default";
         let project = get_project_info(project_name, &global_config).unwrap();
@@ -109,7 +586,189 @@ exclude = [".git", "prometheus.yaml"]
                 src: tilde("~/Desktop/cloud/").into_owned(),
                 dest: "~/qdata-cloud/".to_string(),
                 exclude: Some(vec![".git".to_string(), "prometheus.yaml".to_string()]),
+                ..Default::default()
             }
         )
     }
+
+    #[test]
+    fn test_extends() {
+        let mut value: Value = r#"global_exclude = [".git"]
+
+[exclude_sets]
+ide = [".idea", ".vscode"]
+
+[[projects]]
+name = "base"
+src = "/tmp"
+dest = "~/base"
+exclude = ["target"]
+watch = true
+transfer = { archive = true, compress = true }
+
+[[projects]]
+name = "web"
+extends = "base"
+dest = "~/web"
+exclude = ["node_modules", "target"]
+exclude_sets = ["ide"]
+transfer = { compress = false }
+
+[[projects]]
+name = "raw"
+extends = "web"
+global_exclude = false
+"#
+            .parse()
+            .unwrap();
+        resolve_extends(&mut value).unwrap();
+        let config: GlobalConfig = value.try_into().unwrap();
+
+        let web = get_project_info("web", &config).unwrap();
+        assert_eq!(web.src, "/tmp");
+        assert_eq!(web.dest, "~/web");
+        assert_eq!(web.watch, Some(true));
+        let transfer = web.transfer.unwrap();
+        assert_eq!((transfer.archive, transfer.compress), (Some(true), Some(false)));
+        let excludes = vec![".git", ".idea", ".vscode", "target", "node_modules"];
+        assert_eq!(web.exclude, Some(excludes.iter().map(|e| e.to_string()).collect()));
+
+        let raw = get_project_info("raw", &config).unwrap();
+        assert_eq!(raw.exclude.unwrap()[0], ".idea");
+        let base = get_project_info("base", &config).unwrap();
+        assert_eq!(base.exclude, Some(vec![".git".to_string(), "target".to_string()]));
+
+        let mut value: Value = r#"[[projects]]
+name = "a"
+extends = "b"
+
+[[projects]]
+name = "b"
+extends = "a"
+"#
+            .parse()
+            .unwrap();
+        assert!(resolve_extends(&mut value).is_err());
+    }
+
+    #[test]
+    fn test_project_for_dir() {
+        let dir = Path::new("/tmp").join(format!("rn_project_for_dir_{}", std::process::id()));
+        fs::create_dir_all(dir.join("web/static/css")).unwrap();
+        let project = |name: &str, src: &Path| Project {
+            name: name.to_string(),
+            src: src.to_string_lossy().into_owned(),
+            dest: "~/".to_string(),
+            ..Default::default()
+        };
+        let config = GlobalConfig {
+            global_user: None,
+            global_password: None,
+            global_key: None,
+            global_port: None,
+            global_dest_root: None,
+            global_exclude: None,
+            global_rsync_args: None,
+            global_filter_file: None,
+            exclude_sets: None,
+            hosts: None,
+            profiles: None,
+            projects: Some(vec![
+                project("all", &dir),
+                project("static", &dir.join("web/static/")),
+                project("missing", &dir.join("missing")),
+            ]),
+        };
+        let name = |path: &Path| project_for_dir(path, &config).map(|p| p.name.clone());
+        assert_eq!(name(&dir.join("web/static/css")), Some("static".to_string()));
+        assert_eq!(name(&dir.join("web")), Some("all".to_string()));
+        assert_eq!(name(Path::new("/")), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand() {
+        env::set_var("RN_TEST_EXPAND", "value");
+        let context = TemplateContext {
+            project: "web".to_string(),
+            host: "ubuntu".to_string(),
+            user: "root".to_string(),
+            git_branch: Some("feature-a".to_string()),
+        };
+        assert_eq!(
+            expand("~/${project}/${git_branch}/${ user }@${host}", &context).unwrap(),
+            "~/web/feature-a/root@ubuntu"
+        );
+        assert_eq!(expand("${RN_TEST_EXPAND}/$$HOME/$1", &context).unwrap(), "value/$HOME/$1");
+        assert!(expand("${RN_TEST_NOT_SET}", &context).is_err());
+        assert!(expand("${project", &context).is_err());
+
+        let mut project = Project {
+            name: "web".to_string(),
+            src: "${RN_TEST_EXPAND}".to_string(),
+            dest: "~/${project}".to_string(),
+            exclude: Some(vec!["${project}.log".to_string()]),
+            ..Default::default()
+        };
+        project.expand(&context).unwrap();
+        assert_eq!(project.src, "value");
+        assert_eq!(project.dest, "~/web");
+        assert_eq!(project.exclude, Some(vec!["web.log".to_string()]));
+        project.src = "/tmp/${git_branch}".to_string();
+        assert!(project.expand(&context).is_err());
+    }
+
+    #[test]
+    fn test_validate_project() {
+        let mut project = Project {
+            name: "test".to_string(),
+            src: "/tmp".to_string(),
+            dest: "~/test".to_string(),
+            exclude: Some(vec![".git".to_string()]),
+            ..Default::default()
+        };
+        assert!(project.validate().is_ok());
+
+        project.exclude = Some(vec!["a(b".to_string()]);
+        assert!(project.validate().is_err());
+
+        project.exclude = None;
+        project.src = "/tmp/rn-not-exist".to_string();
+        assert!(project.validate().is_err());
+    }
+
+    #[test]
+    fn test_apply_host() {
+        let config: GlobalConfig = r#"[hosts.lab]
+bandwidth_limit = "2m"
+
+[hosts."192.168.1.20"]
+profile = "wan"
+compress = false
+
+[profiles.wan]
+bandwidth_limit = "5m"
+
+[[projects]]
+name = "a"
+src = "/tmp"
+dest = "~/a"
+transfer = { compress = true }
+"#
+            .parse::<Value>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let transfer = |server: &str, address: &str| {
+            let mut project = get_project_info("a", &config).unwrap();
+            let profile = config.apply_host(&mut project, server, address).unwrap();
+            let transfer = project.transfer.unwrap();
+            (profile, transfer.compress, transfer.bwlimit)
+        };
+        // the project overrides the lan profile, the host the project
+        assert_eq!(transfer("lab", "10.10.20.20"), ("lan".to_string(), Some(true), Some("2m".to_string())));
+        assert_eq!(transfer("q20", "192.168.1.20"), ("wan".to_string(), Some(false), Some("5m".to_string())));
+        assert_eq!(transfer("remote", "8.8.8.8"), ("wan".to_string(), Some(true), Some("5m".to_string())));
+        assert!(config.profile("slow").is_err());
+    }
 }
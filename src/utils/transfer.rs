@@ -0,0 +1,283 @@
+use errors::*;
+use regex::Regex;
+
+/// the keys of TransferOptions
+pub const TRANSFER_KEYS: &[&str] = &[
+    "archive",
+    "perms",
+    "owner",
+    "group",
+    "symlinks",
+    "chmod",
+    "chown",
+    "xattrs",
+    "acls",
+    "compress",
+    "bwlimit",
+    "checksum",
+];
+
+/// the rsync options rn sets itself or relies on, they can not be in rsync_args
+const DENIED_RSYNC_ARGS: &[&str] = &[
+    "--remove-source-files",
+    // the older name of --remove-source-files
+    "--remove-sent-files",
+    "--rsh",
+    "--files-from",
+    "--from0",
+    "--out-format",
+    "--log-format",
+    "--info",
+    "--quiet",
+    "--partial-dir",
+    "--link-dest",
+    "--list-only",
+    "--only-write-batch",
+    "--read-batch",
+    "--server",
+    "--sender",
+    "--daemon",
+];
+
+/// the short options of DENIED_RSYNC_ARGS
+const DENIED_SHORT_ARGS: &[char] = &['e', 'q', '0'];
+/// the short options taking a value, the rest of the argument is the value:
+/// --block-size, --temp-dir, --filter, --remote-option and --modify-window
+const VALUE_SHORT_ARGS: &[char] = &['B', 'T', 'f', 'M', '@'];
+
+/// check the extra rsync arguments of a project, they must be options, the
+/// values given as `--option=value`
+pub fn check_rsync_args(args: &[String]) -> Result<()> {
+    for arg in args.iter() {
+        if arg.starts_with("--") {
+            let name = arg.splitn(2, '=').next().unwrap_or("");
+            if DENIED_RSYNC_ARGS.contains(&name) {
+                bail!("{} can not be used, rn sets it or relies on it not being set", name);
+            }
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            for (i, c) in arg.char_indices().skip(1) {
+                if DENIED_SHORT_ARGS.contains(&c) {
+                    bail!("-{} in {} can not be used, rn sets it or relies on it not being set", c, arg);
+                }
+                if VALUE_SHORT_ARGS.contains(&c) {
+                    if i + 1 == arg.len() {
+                        bail!("{} needs a value, give it as -{}value", arg, c);
+                    }
+                    break;
+                }
+            }
+        } else {
+            bail!("{:?} is not an option, give the values as --option=value", arg);
+        }
+    }
+    Ok(())
+}
+
+/// what is done with the symlinks in src
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// sync the files they point to
+    Copy,
+    /// sync them as symlinks
+    Preserve,
+    /// do not sync them
+    Skip,
+}
+
+/// how the files are transferred, a setting which is not set keeps the
+/// default: recursive, with the modification times
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct TransferOptions {
+    /// the permissions, times, owner, group, symlinks and devices
+    pub archive: Option<bool>,
+    pub perms: Option<bool>,
+    /// only works if the remote user is root
+    pub owner: Option<bool>,
+    pub group: Option<bool>,
+    pub symlinks: Option<SymlinkPolicy>,
+    /// change the permissions, e.g. "D755,F644" or "u+x"
+    pub chmod: Option<String>,
+    /// set the owner and group, e.g. "www-data:www-data"
+    pub chown: Option<String>,
+    pub xattrs: Option<bool>,
+    pub acls: Option<bool>,
+    pub compress: Option<bool>,
+    /// in KiB/s, or with a suffix, e.g. "1.5m"
+    pub bwlimit: Option<String>,
+    /// compare the content instead of the size and time to find the changed files
+    pub checksum: Option<bool>,
+}
+
+fn toggle(args: &mut Vec<String>, value: Option<bool>, name: &str) {
+    match value {
+        Some(true) => args.push(format!("--{}", name)),
+        Some(false) => args.push(format!("--no-{}", name)),
+        None => {}
+    }
+}
+
+impl TransferOptions {
+    /// the settings of over, and the ones of self which over does not set
+    pub fn merge(&self, over: &TransferOptions) -> TransferOptions {
+        TransferOptions {
+            archive: over.archive.or(self.archive),
+            perms: over.perms.or(self.perms),
+            owner: over.owner.or(self.owner),
+            group: over.group.or(self.group),
+            symlinks: over.symlinks.or(self.symlinks),
+            chmod: over.chmod.clone().or_else(|| self.chmod.clone()),
+            chown: over.chown.clone().or_else(|| self.chown.clone()),
+            xattrs: over.xattrs.or(self.xattrs),
+            acls: over.acls.or(self.acls),
+            compress: over.compress.or(self.compress),
+            bwlimit: over.bwlimit.clone().or_else(|| self.bwlimit.clone()),
+            checksum: over.checksum.or(self.checksum),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref chmod) = self.chmod {
+            let re = Regex::new(r"^([DF]?([ugoa]*[-+=][rwxXst]*|[0-7]{3,4}))(,[DF]?([ugoa]*[-+=][rwxXst]*|[0-7]{3,4}))*$")?;
+            if !re.is_match(chmod) {
+                bail!("invalid chmod {:?}, e.g. \"D755,F644\" or \"u+x\"", chmod);
+            }
+        }
+        if let Some(ref chown) = self.chown {
+            let re = Regex::new(r"^[\w.-]*(:[\w.-]*)?$")?;
+            if chown.is_empty() || chown == ":" || !re.is_match(chown) {
+                bail!("invalid chown {:?}, e.g. \"user:group\", \"user\" or \":group\"", chown);
+            }
+        }
+        if let Some(ref bwlimit) = self.bwlimit {
+            let re = Regex::new(r"^\d+(\.\d+)?[KMGkmg]?$")?;
+            if !re.is_match(bwlimit) {
+                bail!("invalid bwlimit {:?}, e.g. \"500\" (KiB/s) or \"1.5m\"", bwlimit);
+            }
+        }
+        Ok(())
+    }
+
+    /// the rsync arguments
+    pub fn rsync_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.archive == Some(true) {
+            args.push("--archive".to_string());
+        } else {
+            args.push("--recursive".to_string());
+            args.push("--times".to_string());
+        }
+        toggle(&mut args, self.perms, "perms");
+        toggle(&mut args, self.owner, "owner");
+        toggle(&mut args, self.group, "group");
+        match self.symlinks {
+            Some(SymlinkPolicy::Copy) => args.push("--copy-links".to_string()),
+            Some(SymlinkPolicy::Preserve) => args.push("--links".to_string()),
+            Some(SymlinkPolicy::Skip) => args.push("--no-links".to_string()),
+            None => {}
+        }
+        if let Some(ref chmod) = self.chmod {
+            args.push(format!("--chmod={}", chmod));
+        }
+        if let Some(ref chown) = self.chown {
+            args.push(format!("--chown={}", chown));
+        }
+        toggle(&mut args, self.xattrs, "xattrs");
+        toggle(&mut args, self.acls, "acls");
+        toggle(&mut args, self.compress, "compress");
+        if let Some(ref bwlimit) = self.bwlimit {
+            args.push(format!("--bwlimit={}", bwlimit));
+        }
+        if self.checksum == Some(true) {
+            args.push("--checksum".to_string());
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rsync_args() {
+        assert_eq!(TransferOptions::default().rsync_args(), vec!["--recursive", "--times"]);
+        let options = TransferOptions {
+            archive: Some(true),
+            owner: Some(false),
+            symlinks: Some(SymlinkPolicy::Copy),
+            chmod: Some("D755,F644".to_string()),
+            compress: Some(true),
+            bwlimit: Some("1.5m".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.rsync_args(),
+            vec![
+                "--archive",
+                "--no-owner",
+                "--copy-links",
+                "--chmod=D755,F644",
+                "--compress",
+                "--bwlimit=1.5m",
+            ]
+        );
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let invalid = |options: TransferOptions| assert!(options.validate().is_err(), "{:?}", options);
+        invalid(TransferOptions {
+            chmod: Some("755; rm -rf /".to_string()),
+            ..Default::default()
+        });
+        invalid(TransferOptions {
+            chown: Some("a b".to_string()),
+            ..Default::default()
+        });
+        invalid(TransferOptions {
+            bwlimit: Some("fast".to_string()),
+            ..Default::default()
+        });
+        let options = TransferOptions {
+            chmod: Some("u+x,go-w".to_string()),
+            chown: Some(":www-data".to_string()),
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn test_check_rsync_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert!(check_rsync_args(&args(&["--inplace", "--fuzzy", "--filter=merge .rsync-filter", "-z"])).is_ok());
+        assert!(check_rsync_args(&args(&["--remove-source-files"])).is_err());
+        assert!(check_rsync_args(&args(&["--remove-sent-files"])).is_err());
+        assert!(check_rsync_args(&args(&["--rsh=ssh"])).is_err());
+        assert!(check_rsync_args(&args(&["-zq"])).is_err());
+        assert!(check_rsync_args(&args(&["--filter", "merge .rsync-filter"])).is_err());
+        assert!(check_rsync_args(&args(&["-0"])).is_err());
+        assert!(check_rsync_args(&args(&["-z0"])).is_err());
+        // the value of a short option is not read as options
+        assert!(check_rsync_args(&args(&["-T/tmp/cache", "-zB1024", "-f- *.eq", "-T/tmp/T"])).is_ok());
+        assert!(check_rsync_args(&args(&["-qT/tmp"])).is_err());
+        assert!(check_rsync_args(&args(&["-T", "/tmp/cache"])).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let base = TransferOptions {
+            archive: Some(true),
+            compress: Some(true),
+            ..Default::default()
+        };
+        let over = TransferOptions {
+            compress: Some(false),
+            ..Default::default()
+        };
+        let merged = base.merge(&over);
+        assert_eq!(merged.archive, Some(true));
+        assert_eq!(merged.compress, Some(false));
+    }
+}
@@ -1,10 +1,15 @@
 extern crate libc;
 
 use errors::*;
-use std::fs::File;
+use std::env;
+use std::fs::{self, File};
 use std::io::Read;
-use std::path::Path;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use regex::Regex;
+use shellexpand::tilde;
 
 
 /// get the content of a file
@@ -22,13 +27,60 @@ pub fn load_file(file_path: &Path) -> Result<String> {
     Ok(contents)
 }
 
-/// check if a path is exclude by regex
-pub fn is_exclude(path: &Path, re_vec: &Vec<Regex>) -> bool {
-    let path_str = path.to_str().unwrap();
-    println!("path to str: {:?}, re_vec: {:?}", path_str, re_vec);
+/// a private directory for the sockets and other runtime files of rn
+pub fn runtime_dir() -> Result<PathBuf> {
+    let uid = unsafe { libc::getuid() };
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("rn"),
+        None => env::temp_dir().join(format!("rn-{}", uid)),
+    };
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    if fs::metadata(&dir)?.uid() != uid {
+        bail!("runtime dir {:?} is owned by another user", dir);
+    }
+    Ok(dir)
+}
+
+/// where rn keeps its data, e.g. the sync history
+pub fn data_dir() -> Result<PathBuf> {
+    let dir = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(tilde("~/.local/share").into_owned()),
+    };
+    let dir = dir.join("rn");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// where the user config of rn is kept
+pub fn config_dir() -> PathBuf {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(tilde("~/.config").into_owned()),
+    };
+    dir.join("rn")
+}
+
+/// keep Ctrl-C in the terminal away from a child by ignoring SIGINT in it,
+/// it stays in the foreground process group to ask for a passphrase or to
+/// confirm a host key on the tty
+pub fn ignore_interrupt(cmd: &mut Command) {
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::signal(libc::SIGINT, libc::SIG_IGN);
+            Ok(())
+        });
+    }
+}
+
+/// check if a path is exclude by regex, a name which is not UTF-8 is
+/// matched with its invalid bytes replaced
+pub fn is_exclude(path: &Path, re_vec: &[Regex]) -> bool {
+    let path_str = path.to_string_lossy();
+    trace!("path to str: {:?}, re_vec: {:?}", path_str, re_vec);
 
     for re in re_vec.iter() {
-        if re.is_match(path_str) {
+        if re.is_match(&path_str) {
             return true;
         }
     }
@@ -36,13 +88,16 @@ pub fn is_exclude(path: &Path, re_vec: &Vec<Regex>) -> bool {
 }
 
 /// create Regex from a given string,
-/// the string is in as glob mode like *.jpg, a/*/*.jpg
+/// the string is in as glob mode like *.jpg, a/*/*.jpg, it matches whole
+/// names: `test` does not exclude `latest`
 pub fn create_re(normal_str: &str) -> Option<Regex> {
     let mut re_string = normal_str.to_string().clone();
     re_string = re_string.replace(".", r"\.").replace("*", r"[^/]*");
     re_string = format!(r"{}($|/)", re_string);
     if re_string.starts_with(r"/") {
         re_string = format!(r"^{}", re_string);
+    } else {
+        re_string = format!(r"(^|/){}", re_string);
     }
     match Regex::new(re_string.as_str()) {
         Ok(re) => Some(re),
@@ -59,6 +114,8 @@ mod tests {
     use std::path::{Path, PathBuf};
     use std::fs::File;
     use std::io::prelude::*;
+    use std::thread;
+    use std::time::Duration;
     use regex::Regex;
 
     #[test]
@@ -78,6 +135,38 @@ mod tests {
         assert!(result.is_err());
     }
 
+    /// the state of a process and whether it ignores SIGINT, from /proc
+    fn proc_state(pid: u32) -> (String, bool) {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+        // "pid (comm) state ...", comm may contain spaces
+        let state = stat.rsplit(')').next().unwrap().split_whitespace().next().unwrap().to_string();
+        let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+        let ignored = status
+            .lines()
+            .find(|line| line.starts_with("SigIgn:"))
+            .and_then(|line| u64::from_str_radix(line["SigIgn:".len()..].trim(), 16).ok())
+            .unwrap();
+        (state, ignored & (1 << (libc::SIGINT - 1)) != 0)
+    }
+
+    #[test]
+    fn test_ignore_interrupt() {
+        // reads from the tty if there is one, a child in a background
+        // process group would be stopped by SIGTTIN
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("read line < /dev/tty; sleep 5");
+        ignore_interrupt(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
+        thread::sleep(Duration::from_millis(300));
+        let (state, ignored) = proc_state(child.id());
+        let group = unsafe { libc::getpgid(child.id() as libc::pid_t) };
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_ne!(state, "T");
+        assert!(ignored);
+        assert_eq!(group, unsafe { libc::getpgid(0) });
+    }
+
     #[test]
     fn test_is_exclude() {
         let mut re_vec: Vec<Regex> = Vec::new();
@@ -129,4 +218,28 @@ mod tests {
         assert!(re.is_match("a/helloabcworld/b"));
         assert!(re.is_match("helloworld"));
     }
+
+    #[test]
+    fn test_create_re_whole_names() {
+        let re = create_re("test").unwrap();
+        assert!(re.is_match("/test"));
+        assert!(re.is_match("/a/test/b.txt"));
+        assert!(!re.is_match("/latest"));
+        assert!(!re.is_match("/a/latest/b.txt"));
+
+        let re = create_re(".git").unwrap();
+        assert!(re.is_match("/.git"));
+        assert!(re.is_match("/a/.git/config"));
+        assert!(!re.is_match("/foo.git"));
+    }
+
+    #[test]
+    fn test_is_exclude_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let excludes = vec![create_re(".git").unwrap()];
+        assert!(is_exclude(Path::new(OsStr::from_bytes(b"/\xff/.git/config")), &excludes));
+        assert!(!is_exclude(Path::new(OsStr::from_bytes(b"/\xff.txt")), &excludes));
+    }
 }
@@ -0,0 +1,502 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use shellexpand::tilde;
+use toml::Value;
+use super::config::{self, Layer, LayeredConfig};
+use super::toml_parser::{GlobalConfig, GLOBAL_KEYS, HOST_KEYS, PROJECT_KEYS};
+use super::agent::can_push;
+use super::transfer::{check_rsync_args, TransferOptions, TRANSFER_KEYS};
+use super::util::load_file;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// a problem found in the config, with where it is if known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// the config file, or the env var
+    pub file: Option<String>,
+    /// 1-based
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file)?;
+            if let Some(line) = self.line {
+                write!(f, "{}:{}:", line, self.column.unwrap_or(1))?;
+            }
+            write!(f, " ")?;
+        }
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}", severity, self.message)
+    }
+}
+
+/// the merged config if it could be loaded, and the problems found
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub config: Option<GlobalConfig>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Validation {
+    pub fn errors<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Diagnostic> + 'a> {
+        Box::new(self.diagnostics.iter().filter(|d| d.severity == Severity::Error))
+    }
+
+    pub fn warnings<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Diagnostic> + 'a> {
+        Box::new(self.diagnostics.iter().filter(|d| d.severity == Severity::Warning))
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+}
+
+/// the column of `key` if the line assigns it
+fn key_column(line: &str, key: &str) -> Option<usize> {
+    let trimmed = line.trim_start();
+    let quoted = format!("\"{}\"", key);
+    let rest = if trimmed.starts_with(&quoted) {
+        &trimmed[quoted.len()..]
+    } else if trimmed.starts_with(key) {
+        &trimmed[key.len()..]
+    } else {
+        return None;
+    };
+    if rest.trim_start().starts_with('=') {
+        Some(line.len() - trimmed.len() + 1)
+    } else {
+        None
+    }
+}
+
+/// where `key = ` is in text (line and column, 1-based), in the top level
+/// or in the [[projects]] tables named project
+fn locate_all(text: &str, project: Option<&str>, key: &str) -> Vec<(usize, usize)> {
+    // the name of each [[projects]] table, and the lines assigning key
+    let mut names: Vec<Option<String>> = Vec::new();
+    let mut found: Vec<(Option<usize>, usize, usize)> = Vec::new();
+    let mut table: Option<usize> = None;
+    let mut other_table = false;
+    for (n, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("[[projects]]") {
+            table = Some(names.len());
+            names.push(None);
+            other_table = false;
+            continue;
+        }
+        if trimmed.starts_with('[') && trimmed.ends_with(']') && !trimmed.contains('"') {
+            other_table = true;
+            continue;
+        }
+        if other_table {
+            continue;
+        }
+        if let Some(index) = table {
+            if let Some(column) = key_column(line, "name") {
+                let value = line[column - 1..].splitn(2, '=').nth(1).unwrap_or("");
+                names[index] = Some(value.trim().trim_matches('"').to_string());
+            }
+        }
+        if let Some(column) = key_column(line, key) {
+            found.push((table, n + 1, column));
+        }
+    }
+    found
+        .into_iter()
+        .filter(|&(table, _, _)| match project {
+            None => table.is_none(),
+            Some(project) => table.and_then(|t| names[t].as_ref()).map_or(false, |name| name == project),
+        })
+        .map(|(_, line, column)| (line, column))
+        .collect()
+}
+
+fn locate(text: &str, project: Option<&str>, key: &str) -> Option<(usize, usize)> {
+    locate_all(text, project, key).into_iter().next()
+}
+
+/// the edit distance of two keys
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == cb {
+                previous
+            } else {
+                1 + previous.min(row[j]).min(row[j + 1])
+            };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+/// the known key closest to a misspelled one
+fn suggest(key: &str, known: &[&str]) -> Option<String> {
+    known
+        .iter()
+        .map(|candidate| (levenshtein(key, candidate), candidate))
+        .filter(|&(distance, _)| distance <= 2.max(key.len() / 4))
+        .min()
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+struct Checker {
+    /// the content of the config files by path
+    texts: BTreeMap<String, String>,
+    validation: Validation,
+}
+
+impl Checker {
+    fn push(&mut self, severity: Severity, file: Option<&str>, position: Option<(usize, usize)>, message: String) {
+        self.validation.diagnostics.push(Diagnostic {
+            severity,
+            file: file.map(String::from),
+            line: position.map(|p| p.0),
+            column: position.map(|p| p.1),
+            message,
+        });
+    }
+
+    fn unknown_key(&mut self, file: &str, project: Option<&str>, key: &str, known: &[&str]) {
+        let position = locate(&self.texts[file], project, key);
+        let mut message = match project {
+            None => format!("unknown key `{}`", key),
+            Some(project) => format!("unknown key `{}` in project {}", key, project),
+        };
+        if let Some(suggestion) = suggest(key, known) {
+            message.push_str(&format!(", did you mean `{}`?", suggestion));
+        }
+        self.push(Severity::Warning, Some(file), position, message);
+    }
+
+    /// syntax errors, unknown keys and duplicate projects of a file
+    fn check_file(&mut self, path: &Path) {
+        let file = path.to_string_lossy().into_owned();
+        let text = match load_file(path) {
+            Ok(text) => text,
+            Err(e) => return self.push(Severity::Error, Some(&file), None, e.to_string()),
+        };
+        self.texts.insert(file.clone(), text.clone());
+        let value: Value = match text.parse() {
+            Ok(value) => value,
+            Err(e) => {
+                let position = e.line_col().map(|(line, column)| (line + 1, column + 1));
+                // the position is reported on its own
+                let message = e.to_string().split(" at line ").next().unwrap_or("").to_string();
+                return self.push(Severity::Error, Some(&file), position, message);
+            }
+        };
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => return,
+        };
+        for key in table.keys().filter(|key| !GLOBAL_KEYS.contains(&key.as_str())) {
+            self.unknown_key(&file, None, key, GLOBAL_KEYS);
+        }
+        for section in &["hosts", "profiles"] {
+            let tables = table.get(*section).and_then(|t| t.as_table());
+            for (name, settings) in tables.into_iter().flat_map(|t| t.iter()) {
+                let keys = settings.as_table().into_iter().flat_map(|s| s.keys());
+                // a profile is only about the transfer
+                let is_host_only = |key: &str| key == "kind" || key == "profile";
+                for key in keys.filter(|key| !HOST_KEYS.contains(&key.as_str()) || (*section == "profiles" && is_host_only(key))) {
+                    self.unknown_key(&file, None, &format!("{}.{}.{}", section, name, key), HOST_KEYS);
+                }
+            }
+        }
+        let projects = match table.get("projects").and_then(|p| p.as_array()) {
+            Some(projects) => projects,
+            None => return,
+        };
+        let mut names: Vec<&str> = Vec::new();
+        for project in projects.iter().filter_map(|p| p.as_table()) {
+            let name = project.get("name").and_then(|n| n.as_str());
+            for key in project.keys().filter(|key| !PROJECT_KEYS.contains(&key.as_str())) {
+                self.unknown_key(&file, name, key, PROJECT_KEYS);
+            }
+            let transfer = project.get("transfer").and_then(|t| t.as_table());
+            for key in transfer.into_iter().flat_map(|t| t.keys()).filter(|key| !TRANSFER_KEYS.contains(&key.as_str())) {
+                self.unknown_key(&file, name, &format!("transfer.{}", key), TRANSFER_KEYS);
+            }
+            if let Some(name) = name {
+                if names.contains(&name) {
+                    let position = locate_all(&text, Some(name), "name").into_iter().nth(1);
+                    self.push(Severity::Error, Some(&file), position, format!("project {} is defined twice", name));
+                }
+                names.push(name);
+            }
+        }
+    }
+
+    /// where a merged setting comes from: the file or env var and the position in the file
+    fn source_of(&self, config: &LayeredConfig, key: &str) -> (Option<String>, Option<(usize, usize)>) {
+        let source = config.sources.get(key).or_else(|| {
+            config.sources.iter().find(|&(k, _)| k.starts_with(&format!("{}.", key))).map(|(_, s)| s)
+        });
+        let source = match source {
+            Some(source) => source,
+            None => return (None, None),
+        };
+        let position = match (source.layer, self.texts.get(&source.origin)) {
+            (Layer::Env, _) | (_, None) => None,
+            (_, Some(text)) => {
+                let parts: Vec<&str> = key.splitn(3, '.').collect();
+                match parts.len() {
+                    1 => locate(text, None, key),
+                    3 if parts[0] == "projects" => locate(text, Some(parts[1]), parts[2]),
+                    _ => None,
+                }
+            }
+        };
+        (Some(source.origin.clone()), position)
+    }
+
+    fn push_at(&mut self, config: &LayeredConfig, severity: Severity, key: &str, message: String) {
+        let (file, position) = self.source_of(config, key);
+        self.push(severity, file.as_ref().map(|f| f.as_str()), position, message);
+    }
+
+    /// the settings which are valid toml but can not work
+    fn check_config(&mut self, layered: &LayeredConfig, config: &GlobalConfig) {
+        if let Some(Err(e)) = config.global_rsync_args.as_ref().map(|args| check_rsync_args(args)) {
+            self.push_at(layered, Severity::Error, "global_rsync_args", e.to_string());
+        }
+        if config.global_password.is_some() && config.global_key.is_some() {
+            self.push_at(
+                layered,
+                Severity::Warning,
+                "global_password",
+                "both global_password and global_key are set, global_password is ignored".to_string(),
+            );
+        }
+        if let Some(ref key) = config.global_key {
+            if !Path::new(tilde(key).as_ref()).is_file() {
+                self.push_at(layered, Severity::Warning, "global_key", format!("global_key {:?} does not exist", key));
+            }
+        }
+        let bandwidth_limit = |limit: &Option<String>| {
+            TransferOptions {
+                bwlimit: limit.clone(),
+                ..Default::default()
+            }.validate()
+        };
+        for (name, profile) in config.profiles.iter().flat_map(|profiles| profiles.iter()) {
+            if let Err(e) = bandwidth_limit(&profile.bandwidth_limit) {
+                self.push_at(layered, Severity::Error, &format!("profiles.{}.bandwidth_limit", name), format!("profile {}: {}", name, e));
+            }
+        }
+        for (name, host) in config.hosts.iter().flat_map(|hosts| hosts.iter()) {
+            if let Some(Err(e)) = host.profile.as_ref().map(|profile| config.profile(profile)) {
+                self.push_at(layered, Severity::Error, &format!("hosts.{}.profile", name), format!("host {}: {}", name, e));
+            }
+            if let Err(e) = bandwidth_limit(&host.bandwidth_limit) {
+                self.push_at(layered, Severity::Error, &format!("hosts.{}.bandwidth_limit", name), format!("host {}: {}", name, e));
+            }
+        }
+        for project in config.projects.iter().flat_map(|projects| projects.iter()) {
+            let key = format!("projects.{}", project.name);
+            // a src with placeholders is checked when it is resolved
+            if !project.src.contains("${") && !Path::new(tilde(&project.src).as_ref()).exists() {
+                self.push_at(
+                    layered,
+                    Severity::Warning,
+                    &format!("{}.src", key),
+                    format!("project {}: src {:?} does not exist", project.name, project.src),
+                );
+            }
+            if project.dest.trim().is_empty() {
+                self.push_at(layered, Severity::Error, &format!("{}.dest", key), format!("project {}: dest is empty", project.name));
+            }
+            if let Err(e) = config.excludes_of(project) {
+                self.push_at(layered, Severity::Error, &format!("{}.exclude_sets", key), e.to_string());
+            }
+            if let Some(Err(e)) = project.rsync_args.as_ref().map(|args| check_rsync_args(args)) {
+                self.push_at(layered, Severity::Error, &format!("{}.rsync_args", key), format!("project {}: {}", project.name, e));
+            }
+            if let Some(Err(e)) = project.transfer.as_ref().map(|transfer| transfer.validate()) {
+                self.push_at(layered, Severity::Error, &format!("{}.transfer", key), format!("project {}: {}", project.name, e));
+            }
+            if project.agent == Some(true) && !can_push(project) {
+                self.push_at(
+                    layered,
+                    Severity::Warning,
+                    &format!("{}.agent", key),
+                    format!("project {}: the agent is not used with release mode, git-tracked, gitignore, filter_file, manifest, rsync_args or the transfer options it can not apply", project.name),
+                );
+            }
+            if project.keep_releases == Some(0) {
+                self.push_at(
+                    layered,
+                    Severity::Error,
+                    &format!("{}.keep_releases", key),
+                    format!("project {}: keep_releases must be at least 1", project.name),
+                );
+            }
+        }
+    }
+}
+
+/// check the config files and the merged config
+pub fn check_files(files: Vec<(Layer, PathBuf)>, vars: &[(String, String)]) -> Validation {
+    let mut checker = Checker {
+        texts: BTreeMap::new(),
+        validation: Validation::default(),
+    };
+    if files.is_empty() {
+        checker.push(Severity::Error, None, None, "no config file found".to_string());
+        return checker.validation;
+    }
+    for &(_, ref path) in files.iter() {
+        checker.check_file(path);
+    }
+    if checker.validation.has_errors() {
+        return checker.validation;
+    }
+    let layered = match LayeredConfig::load_from(files, vars) {
+        Ok(layered) => layered,
+        Err(e) => {
+            checker.push(Severity::Error, None, None, e.to_string());
+            return checker.validation;
+        }
+    };
+    match layered.config() {
+        Ok(config) => {
+            checker.check_config(&layered, &config);
+            checker.validation.config = Some(config);
+        }
+        Err(e) => {
+            // the toml error names the key, e.g. "... for key `global_port`"
+            let message = e.iter().last().map(|e| e.to_string()).unwrap_or_default();
+            let key = message.rsplit('`').nth(1).unwrap_or("").to_string();
+            checker.push_at(&layered, Severity::Error, &key, message);
+        }
+    }
+    checker.validation
+}
+
+/// check all the config layers, config_path is the file given by --config
+pub fn check(config_path: &Path) -> Validation {
+    let vars: Vec<(String, String)> = env::vars().collect();
+    check_files(config::layer_files(config_path), &vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    fn check_text(name: &str, text: &str) -> Validation {
+        let path = Path::new("/tmp").join(format!("rn_validate_{}_{}.toml", name, process::id()));
+        fs::write(&path, text).unwrap();
+        let validation = check_files(vec![(Layer::File, path.clone())], &[]);
+        fs::remove_file(&path).unwrap();
+        validation
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let validation = check_text("syntax", "global_user = \"root\"\nglobal_port = \n");
+        assert!(validation.has_errors());
+        assert_eq!(validation.diagnostics[0].line, Some(2));
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let validation = check_text(
+            "unknown",
+            r#"global_usr = "root"
+
+[[projects]]
+name = "a"
+src = "/tmp"
+dest = "/root/a"
+exlude = [".git"]
+
+[[projects]]
+name = "a"
+src = "/tmp"
+dest = "/root/b"
+"#,
+        );
+        let messages: Vec<String> = validation.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert!(messages[0].ends_with(":1:1: warning: unknown key `global_usr`, did you mean `global_user`?"));
+        assert!(messages[1].ends_with(":7:1: warning: unknown key `exlude` in project a, did you mean `exclude`?"));
+        assert!(messages[2].ends_with(":10:1: error: project a is defined twice"));
+    }
+
+    #[test]
+    fn test_check_config() {
+        let validation = check_text(
+            "config",
+            r#"global_password = "test"
+global_key = "/tmp/rn-not-exist"
+global_port = "22"
+"#,
+        );
+        let diagnostic = &validation.diagnostics[0];
+        assert_eq!(diagnostic.line, Some(3));
+        assert!(diagnostic.message.contains("global_port"));
+
+        let validation = check_text(
+            "semantic",
+            r#"global_password = "test"
+global_key = "/tmp/rn-not-exist"
+
+[[projects]]
+name = "a"
+src = "/tmp/rn-not-exist"
+dest = "/root/a"
+"#,
+        );
+        assert!(!validation.has_errors());
+        let positions: Vec<Option<usize>> = validation.warnings().map(|d| d.line).collect();
+        assert_eq!(positions, vec![Some(1), Some(2), Some(6)]);
+    }
+
+    #[test]
+    fn test_check_hosts() {
+        let validation = check_text(
+            "hosts",
+            r#"[hosts.lab]
+profile = "slow"
+bandwidth_limit = "fast"
+
+[profiles.wan]
+profile = "lan"
+"#,
+        );
+        let messages: Vec<&str> = validation.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages[0], "unknown key `profiles.wan.profile`");
+        assert!(messages[1].starts_with("host lab: unknown profile \"slow\""));
+        assert!(messages[2].starts_with("host lab: invalid bwlimit \"fast\""));
+    }
+
+    #[test]
+    fn test_suggest() {
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(suggest("dst", PROJECT_KEYS), Some("dest".to_string()));
+        assert_eq!(suggest("something", PROJECT_KEYS), None);
+    }
+}
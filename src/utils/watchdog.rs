@@ -1,63 +1,645 @@
 use errors::*;
-use super::{sshconfig, rsync, toml_parser};
-use std::time::Duration;
-use std::sync::mpsc::{Sender, Receiver};
-use notify::{self, Watcher, RecursiveMode, DebouncedEvent, RecommendedWatcher};
-
-pub struct WatchDog<'a, 'b> {
-    pub project: &'a toml_parser::Project,
-    pub host: &'b sshconfig::Host,
+use super::{agent, history, output, sshconfig, rsync, signal, ssh, toml_parser};
+use super::util::{create_re, is_exclude};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use notify::{Watcher, RecursiveMode, DebouncedEvent, RecommendedWatcher};
+
+/// how often the watch loop wakes up to check for signals
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// reload the project and the host from the config file
+pub type Loader = Box<dyn Fn() -> Result<(toml_parser::Project, sshconfig::Host)> + Send>;
+
+/// what a running WatchDog has done so far
+#[derive(Debug, Clone, Default)]
+pub struct WatchStatus {
+    pub syncs: u64,
+    pub last_sync: Option<SystemTime>,
+    pub last_error: Option<String>,
+}
+
+/// shared with the daemon to control a WatchDog running in another thread
+#[derive(Debug, Default)]
+pub struct Control {
+    pub paused: AtomicBool,
+    pub status: Mutex<WatchStatus>,
+    /// set to stop watching, like SIGTERM but for this WatchDog only
+    pub stopped: AtomicBool,
+}
+
+impl Control {
+    /// stop watching after the current event
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// hooks into the watch loop of a WatchDog, called in the order of
+/// `WatchDog::handlers`, they do nothing by default
+pub trait WatchHandler: Send {
+    /// a change in src which is not excluded, return false to ignore it,
+    /// the handlers after this one are not called then
+    fn on_event(&mut self, _project: &toml_parser::Project, _event: &DebouncedEvent) -> bool {
+        true
+    }
+
+    /// before a change is synced, return false to skip the sync
+    fn before_sync(&mut self, _project: &toml_parser::Project, _host: &sshconfig::Host) -> bool {
+        true
+    }
+
+    fn after_sync(&mut self, _project: &toml_parser::Project, _report: &rsync::SyncReport) {}
+
+    /// a sync failed, watching goes on
+    fn on_error(&mut self, _project: &toml_parser::Project, _host: &sshconfig::Host, _error: &Error) {}
+
+    /// the settings were reloaded from the config files
+    fn on_reload(&mut self, _project: &toml_parser::Project) {}
+}
+
+/// logs the errors and writes the events to the output, what a WatchDog
+/// does without any other handler
+pub struct DefaultHandler;
+
+impl WatchHandler for DefaultHandler {
+    fn on_event(&mut self, project: &toml_parser::Project, event: &DebouncedEvent) -> bool {
+        output::emit(&output::Event::Change {
+            project: &project.name,
+            paths: event_paths(event).iter().map(|path| path.to_string_lossy().into_owned()).collect(),
+        });
+        true
+    }
+
+    fn after_sync(&mut self, _project: &toml_parser::Project, report: &rsync::SyncReport) {
+        output::emit(&output::Event::Sync(report));
+    }
+
+    fn on_error(&mut self, project: &toml_parser::Project, host: &sshconfig::Host, error: &Error) {
+        output::emit(&output::Event::Error {
+            project: &project.name,
+            host: &host.hostname,
+            message: error.to_string(),
+        });
+        error!("error: {}", error);
+        for e in error.iter().skip(1) {
+            error!("caused by: {}", e);
+        }
+        if let Some(backtrace) = error.backtrace() {
+            error!("backtrace: {:?}", backtrace);
+        }
+    }
+
+    fn on_reload(&mut self, project: &toml_parser::Project) {
+        output::emit(&output::Event::Reload { project: &project.name });
+    }
+}
+
+/// what happens while watching, given to the closure of an Observer
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    /// files changed in src
+    Change(Vec<PathBuf>),
+    Sync(rsync::SyncReport),
+    /// a sync failed, watching goes on
+    Error(String),
+    /// the settings were reloaded
+    Reload,
+}
+
+/// a handler giving the WatchEvents to a closure, watching stops when it
+/// returns false
+pub struct Observer<F> {
+    on_event: F,
+    control: Arc<Control>,
+}
+
+impl<F> Observer<F>
+where
+    F: FnMut(&WatchEvent) -> bool + Send,
+{
+    /// control is the one of the WatchDog, to stop it
+    pub fn new(on_event: F, control: Arc<Control>) -> Self {
+        Observer { on_event, control }
+    }
+
+    fn observe(&mut self, event: WatchEvent) {
+        if !(self.on_event)(&event) {
+            self.control.stop();
+        }
+    }
+}
+
+impl<F> WatchHandler for Observer<F>
+where
+    F: FnMut(&WatchEvent) -> bool + Send,
+{
+    fn on_event(&mut self, _project: &toml_parser::Project, event: &DebouncedEvent) -> bool {
+        self.observe(WatchEvent::Change(event_paths(event).into_iter().cloned().collect()));
+        true
+    }
+
+    fn after_sync(&mut self, _project: &toml_parser::Project, report: &rsync::SyncReport) {
+        self.observe(WatchEvent::Sync(report.clone()));
+    }
+
+    fn on_error(&mut self, _project: &toml_parser::Project, _host: &sshconfig::Host, error: &Error) {
+        self.observe(WatchEvent::Error(error.to_string()));
+    }
+
+    fn on_reload(&mut self, _project: &toml_parser::Project) {
+        self.observe(WatchEvent::Reload);
+    }
+}
+
+pub struct WatchDog {
+    pub project: toml_parser::Project,
+    pub host: sshconfig::Host,
     pub tx: Sender<DebouncedEvent>,
     pub rx: Receiver<DebouncedEvent>,
+    /// used to reload the settings on SIGHUP or when the config file changes
+    pub loader: Option<Loader>,
+    /// the config files, a change reloads the settings
+    pub config_paths: Vec<PathBuf>,
+    pub control: Arc<Control>,
+    /// called in order, `DefaultHandler` first
+    pub handlers: Vec<Box<dyn WatchHandler>>,
+    /// append the syncs to the history of the project, off by default
+    pub history: bool,
+    /// stop on SIGINT or SIGTERM and reload on SIGHUP, on by default
+    pub signals: bool,
+    excludes: Vec<Regex>,
+    /// the ssh connection kept up while watching
+    master: Option<ssh::Master>,
+    /// pushes the changed files with `agent = true`
+    agent: Option<agent::Agent>,
+}
+
+fn build_excludes(project: &toml_parser::Project) -> Vec<Regex> {
+    match project.exclude {
+        None => Vec::new(),
+        Some(ref exclude) => exclude.iter().filter_map(|pattern| create_re(pattern)).collect(),
+    }
+}
+
+/// the paths touched by an event
+fn event_paths(event: &DebouncedEvent) -> Vec<&PathBuf> {
+    match event {
+        &DebouncedEvent::NoticeWrite(ref path) |
+        &DebouncedEvent::NoticeRemove(ref path) |
+        &DebouncedEvent::Create(ref path) |
+        &DebouncedEvent::Write(ref path) |
+        &DebouncedEvent::Chmod(ref path) |
+        &DebouncedEvent::Remove(ref path) => vec![path],
+        &DebouncedEvent::Rename(ref from, ref to) => vec![from, to],
+        &DebouncedEvent::Error(_, Some(ref path)) => vec![path],
+        _ => vec![],
+    }
 }
 
+/// a master connection to the host, the syncs log in by themselves without it
+fn open_master(host: &sshconfig::Host) -> Option<ssh::Master> {
+    if !host.is_ssh() {
+        return None;
+    }
+    match ssh::Master::new(host) {
+        Ok(master) => Some(master),
+        Err(e) => {
+            warn!("{}, each sync logs in by itself", e);
+            None
+        }
+    }
+}
 
-impl<'a, 'b> WatchDog<'a, 'b> {
-    fn handle_events(&mut self, event: &DebouncedEvent, delete: bool) {
-        if let Err(ref e) = self.do_handle_events(event, delete) {
-            error!("error: {}", e);
-            for e in e.iter().skip(1) {
-                error!("caused by: {}", e);
+impl WatchDog {
+    pub fn new(project: toml_parser::Project, host: sshconfig::Host) -> Self {
+        let (tx, rx) = channel();
+        let excludes = build_excludes(&project);
+        WatchDog {
+            project,
+            host,
+            tx,
+            rx,
+            loader: None,
+            config_paths: Vec::new(),
+            control: Arc::new(Control::default()),
+            handlers: vec![Box::new(DefaultHandler)],
+            history: false,
+            signals: true,
+            excludes,
+            master: None,
+            agent: None,
+        }
+    }
+
+    /// add a handler after the others
+    pub fn add_handler<H: WatchHandler + 'static>(&mut self, handler: H) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    fn handle_events(&mut self, watcher: &mut RecommendedWatcher, event: &DebouncedEvent, delete: bool) {
+        if let Err(ref e) = self.do_handle_events(watcher, event, delete) {
+            if let Ok(mut status) = self.control.status.lock() {
+                status.last_error = Some(e.to_string());
             }
-            if let Some(backtrace) = e.backtrace() {
-                error!("backtrace: {:?}", backtrace);
+            let (project, host) = (&self.project, &self.host);
+            for handler in self.handlers.iter_mut() {
+                handler.on_error(project, host, e);
             }
         }
     }
 
-    fn watch(&mut self, delete: bool) {
-        // block to wait file change
-        match self.rx.recv() {
+    fn watch(&mut self, watcher: &mut RecommendedWatcher, delete: bool) {
+        // block to wait file change, wake up regularly to check for signals
+        match self.rx.recv_timeout(POLL_INTERVAL) {
             Ok(event) => {
-                self.handle_events(&event, delete);
+                self.handle_events(watcher, &event, delete);
             }
+            Err(RecvTimeoutError::Timeout) => {}
             Err(e) => error!("watch error: {:?}", e),
         }
     }
 
-    pub fn start(&mut self, delete: bool) -> notify::Result<()> {
+    /// watch until SIGINT or SIGTERM is received, unless signals is off, or
+    /// the WatchDog is stopped
+    pub fn start(&mut self, delete: bool) -> Result<()> {
         let mut watcher: RecommendedWatcher =
             Watcher::new(self.tx.clone(), Duration::from_secs(2))?;
         watcher.watch(
             self.project.src.as_str(),
             RecursiveMode::Recursive,
         )?;
-        loop {
-            self.watch(delete);
+        self.watch_config(&mut watcher)?;
+        self.master = open_master(&self.host);
+        self.start_agent();
+        let mut reload_generation = signal::reload_generation();
+        while !(self.signals && signal::shutdown_requested()) && !self.control.is_stopped() {
+            if self.signals && signal::reload_generation() != reload_generation {
+                reload_generation = signal::reload_generation();
+                self.reload(&mut watcher);
+            }
+            self.watch(&mut watcher, delete);
+        }
+        info!("stop watching {}", self.project.src);
+        self.agent = None;
+        self.master = None;
+        Ok(())
+    }
+
+    /// the host to sync to, through the master connection if it is up
+    fn connection(&mut self) -> sshconfig::Host {
+        if let Some(ref mut master) = self.master {
+            match master.ensure() {
+                Ok(()) => return master.host(),
+                Err(e) => warn!("{}, log in without it", e),
+            }
+        }
+        self.host.clone()
+    }
+
+    /// start the agent of a project with `agent = true`, rsync is used without it
+    fn start_agent(&mut self) {
+        self.agent = None;
+        if self.project.agent != Some(true) {
+            return;
+        }
+        if !agent::can_push(&self.project) {
+            warn!("{}: the agent can not be used with these settings, sync with rsync", self.project.name);
+            return;
+        }
+        let host = self.connection();
+        match agent::Agent::start(&host, &self.project) {
+            Ok(agent) => self.agent = Some(agent),
+            Err(e) => warn!("{}: start the agent failed, sync with rsync: {}", self.project.name, e),
+        }
+    }
+
+    /// the path relative to src, None if it is not in src or is excluded
+    fn relative(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.project.src).ok()?;
+        if relative.as_os_str().is_empty() || is_exclude(&Path::new("/").join(relative), &self.excludes) {
+            return None;
         }
+        relative.to_str().map(String::from)
     }
 
-    fn do_handle_events(&mut self, event: &DebouncedEvent, delete: bool) -> Result<()> {
+    /// push the change of a file or an empty directory with the agent,
+    /// None if rsync is needed
+    fn push(&mut self, event: &DebouncedEvent, delete: bool) -> Option<rsync::SyncReport> {
+        if self.agent.is_none() {
+            return None;
+        }
+        let started = Instant::now();
+        let mut report = rsync::SyncReport {
+            project: self.project.name.clone(),
+            host: self.host.hostname.clone(),
+            ..Default::default()
+        };
+        let result = match self.push_event(event, delete, &mut report) {
+            Some(result) => result,
+            None => return None,
+        };
+        if let Err(e) = result {
+            warn!("{}: push with the agent failed, sync with rsync: {}", self.project.name, e);
+            if self.agent.as_ref().map_or(false, |agent| agent.is_broken()) {
+                self.agent = None;
+            }
+            return None;
+        }
+        let elapsed = started.elapsed();
+        report.duration = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        report.stats.files_transferred = report.files.len() as u64;
+        report.stats.bytes_sent = report.bytes;
+        info!(
+            "{}: {} files pushed, {} deleted, {} bytes sent by the agent",
+            report.project,
+            report.files.len(),
+            report.deleted.len(),
+            report.bytes
+        );
+        Some(report)
+    }
+
+    fn push_event(&mut self, event: &DebouncedEvent, delete: bool, report: &mut rsync::SyncReport) -> Option<Result<()>> {
+        let paths = event_paths(event);
+        let relatives: Vec<String> = paths.iter().filter_map(|path| self.relative(path)).collect();
+        if relatives.len() != paths.len() {
+            return None;
+        }
+        let transfer = self.project.transfer.clone().unwrap_or_default();
+        let perms = transfer.perms.or(transfer.archive) == Some(true);
+        let agent = self.agent.as_mut()?;
+        let result = match (event, relatives.as_slice()) {
+            (&DebouncedEvent::Rename(..), [from, to]) if delete => {
+                report.files.push(to.clone());
+                report.deleted.push(from.clone());
+                agent.rename(from, to)
+            }
+            // without --delete the old file stays, like rsync leaves it
+            (&DebouncedEvent::Rename(_, ref to), [_, relative]) |
+            (&DebouncedEvent::Create(ref to), [relative]) |
+            (&DebouncedEvent::Write(ref to), [relative]) => match fs::symlink_metadata(to) {
+                Ok(ref metadata) if metadata.is_file() => agent.send(relative, to, perms).map(|bytes| {
+                    report.files.push(relative.clone());
+                    report.bytes += bytes;
+                }),
+                Ok(ref metadata) if metadata.is_dir() => match fs::read_dir(to).map(|mut entries| entries.next().is_none()) {
+                    Ok(true) => agent.mkdir(relative),
+                    _ => return None,
+                },
+                // a symlink, or a file already gone
+                _ => return None,
+            },
+            (&DebouncedEvent::Remove(_), [relative]) if delete => {
+                report.deleted.push(relative.clone());
+                agent.delete(relative)
+            }
+            (&DebouncedEvent::Remove(_), _) => Ok(()),
+            (&DebouncedEvent::Chmod(ref path), [relative]) if perms => match fs::metadata(path) {
+                Ok(metadata) => agent.chmod(relative, metadata.permissions().mode() & 0o7777),
+                Err(_) => return None,
+            },
+            // the permissions are not synced
+            (&DebouncedEvent::Chmod(_), _) => Ok(()),
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    /// watch the directories of the config files, editors often replace a
+    /// file instead of writing it, which would end a watch on the file itself
+    fn watch_config(&mut self, watcher: &mut RecommendedWatcher) -> Result<()> {
+        let src = Path::new(&self.project.src).canonicalize()?;
+        let mut config_paths = Vec::new();
+        let mut dirs: Vec<PathBuf> = Vec::new();
+        for path in self.config_paths.iter() {
+            let config_path = path.canonicalize()?;
+            if let Some(dir) = config_path.parent() {
+                // a .rn.toml in src is already watched with it
+                if !dir.starts_with(&src) && !dirs.iter().any(|d| d == dir) {
+                    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+                    dirs.push(dir.to_path_buf());
+                }
+            }
+            debug!("watch config file: {:?}", config_path);
+            config_paths.push(config_path);
+        }
+        self.config_paths = config_paths;
+        Ok(())
+    }
+
+    fn is_config_event(&self, event: &DebouncedEvent) -> bool {
+        event_paths(event)
+            .iter()
+            .any(|path| self.config_paths.iter().any(|config_path| *path == config_path))
+    }
+
+    /// true if all the paths of the event are excluded by the project
+    fn is_excluded_event(&self, event: &DebouncedEvent) -> bool {
+        let paths = event_paths(event);
+        if paths.is_empty() || self.excludes.is_empty() {
+            return false;
+        }
+        paths.iter().all(|path| match path.strip_prefix(&self.project.src) {
+            // match against "/relative/path" so that patterns starting with "/"
+            // are anchored at the project root like rsync does
+            Ok(relative) => is_exclude(&Path::new("/").join(relative), &self.excludes),
+            Err(_) => false,
+        })
+    }
+
+    /// reload the settings, keep the current ones if anything goes wrong
+    fn reload(&mut self, watcher: &mut RecommendedWatcher) {
+        let loaded = match self.loader {
+            None => return,
+            Some(ref loader) => loader(),
+        };
+        let (project, host) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                error!("reload settings failed, keep the current ones: {}", e);
+                return;
+            }
+        };
+        if project.src != self.project.src {
+            if let Err(e) = watcher.watch(project.src.as_str(), RecursiveMode::Recursive) {
+                error!("watch {} failed, keep the current settings: {:?}", project.src, e);
+                return;
+            }
+            if let Err(e) = watcher.unwatch(self.project.src.as_str()) {
+                warn!("unwatch {} failed: {:?}", self.project.src, e);
+            }
+        }
+        info!("settings reloaded, project: {:?}", project);
+        for handler in self.handlers.iter_mut() {
+            handler.on_reload(&project);
+        }
+        self.excludes = build_excludes(&project);
+        self.project = project;
+        if self.master.as_ref().map_or(false, |master| !master.is_for(&host)) {
+            // close the connection to the old host first
+            self.agent = None;
+            self.master = None;
+            self.master = open_master(&host);
+        }
+        self.host = host;
+        // dest or the other settings of the agent may have changed
+        self.start_agent();
+    }
+
+    fn do_handle_events(&mut self, watcher: &mut RecommendedWatcher, event: &DebouncedEvent, delete: bool) -> Result<()> {
         match event {
             &DebouncedEvent::NoticeWrite(ref _path) |
             &DebouncedEvent::NoticeRemove(ref _path) => {},
             &DebouncedEvent::Error(ref e, ref path) => {
                 error!("error in event: file: {:?}, error: {:?}", &path, e);
             },
+            _ if self.is_config_event(event) => {
+                info!("config file changed, reload settings");
+                self.reload(watcher);
+            },
+            _ if self.is_excluded_event(event) => {
+                debug!("ignore excluded event: {:?}", event);
+            },
+            _ if self.control.paused.load(Ordering::SeqCst) => {
+                debug!("paused, ignore event: {:?}", event);
+            },
             _ => {
-                rsync::sync(self.host, self.project, delete)?;
+                let (project, host) = (&self.project, &self.host);
+                if !self.handlers.iter_mut().all(|handler| handler.on_event(project, event)) {
+                    debug!("a handler ignores the event: {:?}", event);
+                    return Ok(());
+                }
+                if !self.handlers.iter_mut().all(|handler| handler.before_sync(project, host)) {
+                    debug!("a handler skips the sync of the event: {:?}", event);
+                    return Ok(());
+                }
+                let result = match self.push(event, delete) {
+                    Some(report) => Ok(report),
+                    None => {
+                        let host = self.connection();
+                        rsync::sync(&host, &self.project, delete)
+                    }
+                };
+                if self.history {
+                    history::record(&self.project, &self.host, &result);
+                }
+                let report = result?;
+                if let Ok(mut status) = self.control.status.lock() {
+                    status.syncs += 1;
+                    status.last_sync = Some(SystemTime::now());
+                    status.last_error = None;
+                }
+                for handler in self.handlers.iter_mut() {
+                    handler.after_sync(&self.project, &report);
+                }
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::container;
+
+    /// records the hooks called, skips the syncs if sync is false
+    struct Recorder {
+        calls: Arc<Mutex<Vec<String>>>,
+        sync: bool,
+    }
+
+    impl WatchHandler for Recorder {
+        fn on_event(&mut self, _project: &toml_parser::Project, event: &DebouncedEvent) -> bool {
+            self.calls.lock().unwrap().push(format!("event {:?}", event_paths(event)));
+            true
+        }
+
+        fn before_sync(&mut self, _project: &toml_parser::Project, _host: &sshconfig::Host) -> bool {
+            self.calls.lock().unwrap().push("before".to_string());
+            self.sync
+        }
+
+        fn after_sync(&mut self, _project: &toml_parser::Project, _report: &rsync::SyncReport) {
+            self.calls.lock().unwrap().push("after".to_string());
+        }
+
+        fn on_error(&mut self, _project: &toml_parser::Project, _host: &sshconfig::Host, _error: &Error) {
+            self.calls.lock().unwrap().push("error".to_string());
+        }
+    }
+
+    /// ignores the changes of the .tmp files
+    struct SkipTmp;
+
+    impl WatchHandler for SkipTmp {
+        fn on_event(&mut self, _project: &toml_parser::Project, event: &DebouncedEvent) -> bool {
+            !event_paths(event).iter().any(|path| path.extension().map_or(false, |ext| ext == "tmp"))
+        }
+    }
+
+    #[test]
+    fn test_handlers() {
+        let dir = Path::new("/tmp").join(format!("rn_watch_handlers_{}", ::std::process::id()));
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let project: toml_parser::Project = ::toml::from_str(&format!(
+            "name = \"web\"\nsrc = \"{}\"\ndest = \"{}/dest/\"\n",
+            src.display(),
+            dir.display()
+        )).unwrap();
+        // syncs whether rsync is installed or not, with tar without it
+        let target = format!("docker://web{}/dest", dir.display());
+        let mut docker = container::parse(&target).unwrap();
+        docker.program = container::fake_docker(&dir).to_string_lossy().into_owned();
+        let mut watchdog = WatchDog::new(project, sshconfig::Host::container(&target, docker));
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        watchdog.handlers.insert(0, Box::new(SkipTmp));
+        watchdog.add_handler(Recorder { calls: calls.clone(), sync: false });
+        let mut watcher: RecommendedWatcher = Watcher::new(watchdog.tx.clone(), Duration::from_secs(2)).unwrap();
+
+        watchdog.handle_events(&mut watcher, &DebouncedEvent::Write(src.join("a.tmp")), false);
+        assert!(calls.lock().unwrap().is_empty());
+        watchdog.handle_events(&mut watcher, &DebouncedEvent::Write(src.join("a.txt")), false);
+        assert_eq!(*calls.lock().unwrap(), vec![format!("event [{:?}]", src.join("a.txt")), "before".to_string()]);
+        assert_eq!(watchdog.control.status.lock().unwrap().syncs, 0);
+
+        calls.lock().unwrap().clear();
+        watchdog.handlers.pop();
+        watchdog.add_handler(Recorder { calls: calls.clone(), sync: true });
+        fs::write(src.join("b.txt"), "b").unwrap();
+        watchdog.handle_events(&mut watcher, &DebouncedEvent::Create(src.join("b.txt")), false);
+        assert_eq!(calls.lock().unwrap()[1..], ["before", "after"]);
+        assert_eq!(fs::read_to_string(dir.join("dest/b.txt")).unwrap(), "b");
+        {
+            let status = watchdog.control.status.lock().unwrap();
+            assert_eq!(status.syncs, 1);
+            assert_eq!(status.last_error, None);
+        }
+
+        // the container can not be reached, the handlers get the error
+        calls.lock().unwrap().clear();
+        if let Some(ref mut docker) = watchdog.host.container {
+            docker.program = dir.join("missing").to_string_lossy().into_owned();
+        }
+        watchdog.handle_events(&mut watcher, &DebouncedEvent::Write(src.join("b.txt")), false);
+        assert_eq!(calls.lock().unwrap()[1..], ["before", "error"]);
+        {
+            let status = watchdog.control.status.lock().unwrap();
+            assert_eq!(status.syncs, 1);
+            assert!(status.last_error.is_some());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use errors::*;
+use std::io::{self, Read, Write};
+
+/// a frame holds at most one file of the agent and its path
+const MAX_FRAME: usize = 65 * 1024 * 1024;
+
+/// write a payload prefixed with its length
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// None at the end of the input
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME {
+        bail!("frame of {} bytes is too large", length);
+    }
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+pub fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+pub fn put_i64(buf: &mut Vec<u8>, n: i64) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+pub fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+/// reads the fields of a payload in order
+pub struct Fields<'a> {
+    payload: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(payload: &'a [u8]) -> Self {
+        Fields { payload }
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.payload.len() < n {
+            bail!("truncated frame");
+        }
+        let (taken, rest) = self.payload.split_at(n);
+        self.payload = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(i64::from_be_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?).chain_err(|| "invalid utf-8 in frame")?)
+    }
+
+    /// the bytes not read yet
+    pub fn len(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// whether all the fields were read
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+}
@@ -0,0 +1,218 @@
+//! a throwaway sshd on localhost for the integration tests, logging in the
+//! user running them with a generated key
+
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the password the stand-in sshpass accepts
+pub const PASSWORD: &str = "secret";
+/// the server of ~/.ssh/config logging in with the key
+pub const KEY_SERVER: &str = "rn-key";
+/// the server of ~/.ssh/config without a key, to log in with a password
+pub const PASSWORD_SERVER: &str = "rn-password";
+
+/// the tests change HOME and PATH, one of them runs at a time
+static ENV: Mutex<()> = Mutex::new(());
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// the path of a program in PATH, or in the sbin directories sshd is often in
+fn find(program: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .chain(["/usr/sbin", "/usr/local/sbin", "/sbin"].iter().map(PathBuf::from))
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn keygen(path: &Path) {
+    let status = Command::new("ssh-keygen")
+        .args(&["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(path)
+        .status()
+        .unwrap();
+    assert!(status.success(), "ssh-keygen {:?} failed", path);
+}
+
+fn write_executable(path: &Path, content: &str) {
+    fs::write(path, content).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// wait until the file exists, or is gone if exists is false
+pub fn wait_for(path: &Path, exists: bool, timeout: Duration) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if path.exists() == exists {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    path.exists() == exists
+}
+
+pub struct Sshd {
+    /// everything of the test is in it, removed at the end
+    pub dir: PathBuf,
+    pub port: u16,
+    pub user: String,
+    child: Child,
+    _env: MutexGuard<'static, ()>,
+}
+
+impl Sshd {
+    /// start sshd with a new home directory for rn, panics if sshd, ssh,
+    /// ssh-keygen or rsync is not installed
+    pub fn start() -> Sshd {
+        for program in &["sshd", "ssh", "ssh-keygen", "rsync"] {
+            assert!(find(program).is_some(), "{} is not installed, needed by the integration tests", program);
+        }
+        let guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = env::temp_dir().join(format!("rn-it-{}-{}", process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_dir_all(&dir);
+        for sub in &["home/.ssh", "bin", "src", "remote"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let user = String::from_utf8(Command::new("id").arg("-un").output().unwrap().stdout).unwrap();
+        let user = user.trim().to_string();
+        let key = dir.join("home/.ssh/id_ed25519");
+        keygen(&dir.join("host_key"));
+        keygen(&key);
+        fs::copy(key.with_extension("pub"), dir.join("authorized_keys")).unwrap();
+
+        let port = free_port();
+        fs::write(
+            dir.join("sshd_config"),
+            format!(
+                "Port {port}\n\
+                 ListenAddress 127.0.0.1\n\
+                 HostKey {dir}/host_key\n\
+                 PidFile {dir}/sshd.pid\n\
+                 AuthorizedKeysFile {dir}/authorized_keys\n\
+                 StrictModes no\n\
+                 UsePAM no\n\
+                 PasswordAuthentication no\n\
+                 LogLevel ERROR\n",
+                port = port,
+                dir = dir.display()
+            ),
+        ).unwrap();
+        let log = fs::File::create(dir.join("sshd.log")).unwrap();
+        let mut child = Command::new(find("sshd").unwrap())
+            .arg("-D")
+            .arg("-e")
+            .arg("-f")
+            .arg(dir.join("sshd_config"))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            let exited = child.try_wait().unwrap().is_some();
+            if exited || started.elapsed() > Duration::from_secs(10) {
+                let _ = child.kill();
+                panic!("sshd did not start: {}", fs::read_to_string(dir.join("sshd.log")).unwrap_or_default());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        // how rn finds the server, ssh itself reads the files of the real home
+        fs::write(
+            dir.join("home/.ssh/config"),
+            format!(
+                "Host {key_server}\n    HostName 127.0.0.1\n    User {user}\n    Port {port}\n    IdentityFile {key}\n\n\
+                 Host {password_server}\n    HostName 127.0.0.1\n    User {user}\n    Port {port}\n",
+                key_server = KEY_SERVER,
+                password_server = PASSWORD_SERVER,
+                user = user,
+                port = port,
+                key = key.display()
+            ),
+        ).unwrap();
+        // sshd can only check the password of a real account, this sshpass
+        // checks the one rn gives it and logs in with the key instead
+        write_executable(
+            &dir.join("bin/sshpass"),
+            &format!(
+                "#!/bin/sh\n\
+                 [ \"$1\" = -e ] && [ \"$SSHPASS\" = {password} ] || {{ echo 'sshpass: wrong password' >&2; exit 5; }}\n\
+                 shift\n\
+                 ssh=$1\n\
+                 shift\n\
+                 exec \"$ssh\" -i {key} -o IdentitiesOnly=yes -o UserKnownHostsFile=/dev/null -o StrictHostKeyChecking=no \"$@\"\n",
+                password = PASSWORD,
+                key = key.display()
+            ),
+        );
+
+        let path = env::var_os("PATH").unwrap_or_default();
+        let path = env::join_paths(Some(dir.join("bin")).into_iter().chain(env::split_paths(&path))).unwrap();
+        env::set_var("PATH", path);
+        env::set_var("HOME", dir.join("home"));
+        env::set_var("XDG_CONFIG_HOME", dir.join("home/.config"));
+        env::set_var("XDG_DATA_HOME", dir.join("home/.local/share"));
+        Sshd {
+            dir,
+            port,
+            user,
+            child,
+            _env: guard,
+        }
+    }
+
+    /// the local directory to sync
+    pub fn src(&self) -> PathBuf {
+        self.dir.join("src")
+    }
+
+    /// the directory the files are synced to over ssh
+    pub fn remote(&self) -> PathBuf {
+        self.dir.join("remote")
+    }
+
+    /// write the config of rn with the project `web` syncing src to remote
+    pub fn config(&self, exclude: &[&str]) -> PathBuf {
+        let path = self.dir.join("rn.toml");
+        let exclude: Vec<String> = exclude.iter().map(|pattern| format!("{:?}", pattern)).collect();
+        fs::write(
+            &path,
+            format!(
+                "[[projects]]\nname = \"web\"\nsrc = \"{}/\"\ndest = \"{}/\"\nexclude = [{}]\n",
+                self.src().display(),
+                self.remote().display(),
+                exclude.join(", ")
+            ),
+        ).unwrap();
+        path
+    }
+
+    /// write a file under src
+    pub fn write(&self, path: &str, content: &str) {
+        let path = self.src().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+}
+
+impl Drop for Sshd {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,62 @@
+extern crate rn;
+
+mod common;
+
+use common::{Sshd, KEY_SERVER, PASSWORD, PASSWORD_SERVER};
+use rn::{HostOptions, SyncOptions};
+use std::fs;
+
+#[test]
+#[ignore = "needs sshd, ssh, ssh-keygen and rsync, run with cargo test -- --ignored"]
+fn test_key_auth() {
+    let sshd = Sshd::start();
+    sshd.write("keep.txt", "keep");
+    sshd.write("sub/c.txt", "c");
+    let config = sshd.config(&[]);
+    rn::run(&config, "web", KEY_SERVER, false, false, HostOptions::default(), SyncOptions::default()).unwrap();
+    assert_eq!(fs::read_to_string(sshd.remote().join("keep.txt")).unwrap(), "keep");
+    assert_eq!(fs::read_to_string(sshd.remote().join("sub/c.txt")).unwrap(), "c");
+}
+
+#[test]
+#[ignore = "needs sshd, ssh, ssh-keygen and rsync, run with cargo test -- --ignored"]
+fn test_password_auth() {
+    let sshd = Sshd::start();
+    sshd.write("keep.txt", "keep");
+    let config = sshd.config(&[]);
+    let options = HostOptions {
+        password: Some("wrong".to_string()),
+        ..Default::default()
+    };
+    assert!(rn::run(&config, "web", PASSWORD_SERVER, false, false, options, SyncOptions::default()).is_err());
+    assert!(!sshd.remote().join("keep.txt").exists());
+
+    let options = HostOptions {
+        password: Some(PASSWORD.to_string()),
+        port: Some(sshd.port),
+        ..Default::default()
+    };
+    rn::run(&config, "web", PASSWORD_SERVER, false, false, options, SyncOptions::default()).unwrap();
+    assert_eq!(fs::read_to_string(sshd.remote().join("keep.txt")).unwrap(), "keep");
+}
+
+#[test]
+#[ignore = "needs sshd, ssh, ssh-keygen and rsync, run with cargo test -- --ignored"]
+fn test_exclude_and_delete() {
+    let sshd = Sshd::start();
+    sshd.write("keep.txt", "keep");
+    sshd.write("a.txt", "a");
+    sshd.write("logs/x.log", "x");
+    fs::write(sshd.remote().join("old.txt"), "old").unwrap();
+    let config = sshd.config(&["a.txt", "*.log"]);
+
+    rn::run(&config, "web", KEY_SERVER, false, false, HostOptions::default(), SyncOptions::default()).unwrap();
+    assert!(sshd.remote().join("keep.txt").is_file());
+    assert!(!sshd.remote().join("a.txt").exists());
+    assert!(!sshd.remote().join("logs/x.log").exists());
+    assert!(sshd.remote().join("old.txt").is_file());
+
+    rn::run(&config, "web", KEY_SERVER, false, true, HostOptions::default(), SyncOptions::default()).unwrap();
+    assert!(sshd.remote().join("keep.txt").is_file());
+    assert!(!sshd.remote().join("old.txt").exists());
+}
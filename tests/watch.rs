@@ -0,0 +1,40 @@
+extern crate rn;
+
+mod common;
+
+use common::{wait_for, Sshd, KEY_SERVER};
+use rn::utils::signal;
+use rn::{HostOptions, SyncOptions};
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+/// in its own test binary, the shutdown it requests can not be undone
+#[test]
+#[ignore = "needs sshd, ssh, ssh-keygen and rsync, run with cargo test -- --ignored"]
+fn test_watch() {
+    let sshd = Sshd::start();
+    sshd.write("first.txt", "first");
+    sshd.write("gone.txt", "gone");
+    let config = sshd.config(&["*.log"]);
+    let watcher = {
+        let config = config.clone();
+        thread::spawn(move || {
+            rn::run(&config, "web", KEY_SERVER, true, true, HostOptions::default(), SyncOptions::default())
+        })
+    };
+    let timeout = Duration::from_secs(20);
+    assert!(wait_for(&sshd.remote().join("first.txt"), true, timeout));
+    assert!(sshd.remote().join("gone.txt").is_file());
+
+    sshd.write("second.txt", "second");
+    sshd.write("debug.log", "log");
+    fs::remove_file(sshd.src().join("gone.txt")).unwrap();
+    assert!(wait_for(&sshd.remote().join("second.txt"), true, timeout));
+    assert!(wait_for(&sshd.remote().join("gone.txt"), false, timeout));
+    assert!(!sshd.remote().join("debug.log").exists());
+    assert_eq!(fs::read_to_string(sshd.remote().join("second.txt")).unwrap(), "second");
+
+    signal::request_shutdown();
+    watcher.join().unwrap().unwrap();
+}